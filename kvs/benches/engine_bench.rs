@@ -0,0 +1,101 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use kvs::{KvEngine, KvStore, KvStoreOptions, LsmEngine, Sync};
+use rand::prelude::*;
+use std::path::Path;
+use std::thread;
+use tempfile::TempDir;
+
+fn set_bench(c: &mut Criterion) {
+    bench_set(c, "kvs_set", |path| KvStore::open(path).unwrap());
+    bench_set(c, "lsm_set", |path| LsmEngine::open(path).unwrap());
+}
+
+fn get_bench(c: &mut Criterion) {
+    bench_get(c, "kvs_get", |path| KvStore::open(path).unwrap());
+    bench_get(c, "lsm_get", |path| LsmEngine::open(path).unwrap());
+}
+
+fn bench_set<E: KvEngine>(c: &mut Criterion, name: &str, open: impl Fn(&Path) -> E) {
+    c.bench_function(name, |b| {
+        b.iter_batched(
+            || {
+                let temp_dir = TempDir::new().unwrap();
+                (open(temp_dir.path()), temp_dir)
+            },
+            |(engine, _temp_dir)| {
+                for i in 1..(1 << 12) {
+                    engine
+                        .set(format!("key{}", i), "value".to_string())
+                        .unwrap();
+                }
+            },
+            BatchSize::SmallInput,
+        )
+    });
+}
+
+fn bench_get<E: KvEngine>(c: &mut Criterion, name: &str, open: impl Fn(&Path) -> E) {
+    let temp_dir = TempDir::new().unwrap();
+    let engine = open(temp_dir.path());
+    let key_count = 1 << 12;
+    for key_i in 1..key_count {
+        engine
+            .set(format!("key{}", key_i), "value".to_string())
+            .unwrap();
+    }
+    let mut rng = SmallRng::from_seed([0; 32]);
+    c.bench_function(name, |b| {
+        b.iter(|| {
+            engine
+                .get(format!("key{}", rng.gen_range(1..key_count)))
+                .unwrap();
+        })
+    });
+}
+
+// Compares a single writer with concurrent writers in the `Sync::Always` mode,
+// where the concurrent writes are acknowledged by shared syncs.
+fn always_sync_bench(c: &mut Criterion) {
+    const WRITES: usize = 1 << 9;
+
+    let mut group = c.benchmark_group("kvs_always_sync_set");
+    group.throughput(Throughput::Elements(WRITES as u64));
+    group.sample_size(10);
+    for &threads in &[1, 8] {
+        group.bench_function(format!("{}_threads", threads), |b| {
+            b.iter_batched(
+                || {
+                    let temp_dir = TempDir::new().unwrap();
+                    let mut options = KvStoreOptions::new();
+                    options.sync(Sync::Always);
+                    (
+                        KvStore::open_with(temp_dir.path(), &options).unwrap(),
+                        temp_dir,
+                    )
+                },
+                |(store, _temp_dir)| {
+                    let handles: Vec<_> = (0..threads)
+                        .map(|thread_i| {
+                            let store = store.clone();
+                            thread::spawn(move || {
+                                for i in 0..WRITES / threads {
+                                    store
+                                        .set(format!("key{}-{}", thread_i, i), "value".to_string())
+                                        .unwrap();
+                                }
+                            })
+                        })
+                        .collect();
+                    for handle in handles {
+                        handle.join().unwrap();
+                    }
+                },
+                BatchSize::PerIteration,
+            )
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, set_bench, get_bench, always_sync_bench);
+criterion_main!(benches);
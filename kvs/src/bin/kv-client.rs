@@ -0,0 +1,160 @@
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::process::exit;
+use std::time::Duration;
+
+use clap::{Parser, Subcommand};
+use kvs::{KvsClient, KvsError, Result, WatchEvent};
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+
+#[derive(Parser, Debug)]
+#[command(name = "kv-client", version, about)]
+struct Opt {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Get the string value of a given string key
+    Get {
+        /// A string key
+        key: String,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Set the value of a string key to a string
+    Set {
+        /// A string key
+        key: String,
+        /// The string value of the key
+        value: String,
+        /// Remove the key after this many seconds
+        #[arg(long, value_name = "SECONDS")]
+        ttl: Option<u64>,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Remove a given string key
+    Rm {
+        /// A string key
+        key: String,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Replace the value of a string key if it equals the expected one
+    Cas {
+        /// A string key
+        key: String,
+        /// The expected value; the key must be absent if omitted
+        #[arg(long)]
+        expected: Option<String>,
+        /// The new value; the key is removed if omitted
+        #[arg(long)]
+        new: Option<String>,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Set the value of a string key unless the key exists
+    Setnx {
+        /// A string key
+        key: String,
+        /// The string value of the key
+        value: String,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+    /// Print the writes to the keys starting with a prefix as they happen
+    Watch {
+        /// A string prefix
+        prefix: String,
+        /// Server address
+        #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+        addr: SocketAddr,
+    },
+}
+
+fn main() {
+    let opt = Opt::parse();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Get { key, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            match client.get_bytes(key.into_bytes())? {
+                Some(value) => {
+                    // values are not necessarily UTF-8, print them as they are.
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(&value)?;
+                    stdout.write_all(b"\n")?;
+                }
+                None => println!("Key not found"),
+            }
+        }
+        Command::Set {
+            key,
+            value,
+            ttl,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            match ttl {
+                Some(ttl) => client.set_with_ttl(key, value, Duration::from_secs(ttl))?,
+                None => client.set(key, value)?,
+            }
+        }
+        Command::Rm { key, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            client.remove(key)?;
+        }
+        Command::Cas {
+            key,
+            expected,
+            new,
+            addr,
+        } => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.compare_and_swap(key, expected, new)? {
+                return Err(KvsError::StringError("Value mismatch".to_owned()));
+            }
+        }
+        Command::Setnx { key, value, addr } => {
+            let mut client = KvsClient::connect(addr)?;
+            if !client.set_if_absent(key, value)? {
+                return Err(KvsError::StringError("Key already exists".to_owned()));
+            }
+        }
+        Command::Watch { prefix, addr } => {
+            let watcher = KvsClient::connect(addr)?.watch(prefix.into_bytes())?;
+            for event in watcher {
+                let mut stdout = io::stdout().lock();
+                match event? {
+                    WatchEvent::Set { key, value } => {
+                        stdout.write_all(b"set ")?;
+                        stdout.write_all(&key)?;
+                        stdout.write_all(b" ")?;
+                        stdout.write_all(&value)?;
+                    }
+                    WatchEvent::Remove { key } => {
+                        stdout.write_all(b"rm ")?;
+                        stdout.write_all(&key)?;
+                    }
+                }
+                stdout.write_all(b"\n")?;
+                stdout.flush()?;
+            }
+        }
+    }
+    Ok(())
+}
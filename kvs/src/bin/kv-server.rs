@@ -0,0 +1,205 @@
+use std::env::current_dir;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+
+use clap::{Parser, ValueEnum};
+use kvs::{
+    EngineMarker, KvEngine, KvStore, KvsError, KvsServer, LsmEngine, MemoryEngine, Result,
+    SledKvsEngine,
+};
+use slog::{error, info, o, Drain, Logger};
+
+const DEFAULT_LISTENING_ADDRESS: &str = "127.0.0.1:4000";
+
+#[derive(Parser, Debug)]
+#[command(name = "kv-server", version, about)]
+struct Opt {
+    /// Address to listen on
+    #[arg(long, value_name = "IP:PORT", default_value = DEFAULT_LISTENING_ADDRESS)]
+    addr: SocketAddr,
+
+    /// Storage engine [default: the engine of the data directory, or kvs]
+    #[arg(long, value_enum, value_name = "ENGINE-NAME")]
+    engine: Option<Engine>,
+
+    /// Snapshot file of the memory engine, loaded at start and written on shutdown
+    #[arg(long, value_name = "FILE")]
+    snapshot: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+    Lsm,
+    Memory,
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+            Engine::Lsm => write!(f, "lsm"),
+            Engine::Memory => write!(f, "memory"),
+        }
+    }
+}
+
+fn main() {
+    // before any thread is spawned, so none of them takes the signals.
+    let signals = ShutdownSignals::block();
+    let decorator = slog_term::TermDecorator::new().stderr().build();
+    let drain = slog_term::FullFormat::new(decorator).build().fuse();
+    let drain = slog_async::Async::new(drain).build().fuse();
+    let logger = Logger::root(drain, o!());
+
+    let opt = Opt::parse();
+    if let Err(e) = run(opt, signals, &logger) {
+        error!(logger, "{}", e);
+        // the async drain is flushed when the logger is dropped.
+        drop(logger);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt, signals: ShutdownSignals, logger: &Logger) -> Result<()> {
+    info!(logger, "kv-server {}", env!("CARGO_PKG_VERSION"));
+    let dir = current_dir()?;
+    let marker = EngineMarker::read(&dir)?;
+    let engine = choose_engine(marker.as_ref(), opt.engine)?;
+    if opt.snapshot.is_some() && engine != Engine::Memory {
+        return Err(KvsError::StringError(format!(
+            "The {} engine does not take a snapshot file",
+            engine
+        )));
+    }
+    info!(logger, "Storage engine: {}", engine);
+    info!(logger, "Listening on {}", opt.addr);
+
+    match engine {
+        Engine::Kvs => {
+            let store = KvStore::open(&dir)?;
+            mark_directory(&dir, marker, engine)?;
+            run_with_engine(store, opt.addr, signals, logger)
+        }
+        Engine::Sled => {
+            let db = SledKvsEngine::open(&dir)?;
+            mark_directory(&dir, marker, engine)?;
+            run_with_engine(db, opt.addr, signals, logger)
+        }
+        Engine::Lsm => {
+            let lsm = LsmEngine::open(&dir)?;
+            mark_directory(&dir, marker, engine)?;
+            run_with_engine(lsm, opt.addr, signals, logger)
+        }
+        Engine::Memory => {
+            let memory = match opt.snapshot {
+                Some(path) => MemoryEngine::with_snapshot(path)?,
+                None => MemoryEngine::new(),
+            };
+            run_with_engine(memory, opt.addr, signals, logger)
+        }
+    }
+}
+
+/// Returns the engine recorded in the data directory, refusing another
+/// requested one, or the requested engine for a fresh directory.
+fn choose_engine(marker: Option<&EngineMarker>, requested: Option<Engine>) -> Result<Engine> {
+    // the memory engine keeps nothing in the directory.
+    if requested == Some(Engine::Memory) {
+        return Ok(Engine::Memory);
+    }
+    let marker = match marker {
+        Some(marker) => marker,
+        None => return Ok(requested.unwrap_or(Engine::Kvs)),
+    };
+    let found = Engine::from_str(&marker.engine, false)
+        .map_err(|_| KvsError::UnknownEngine(marker.engine.clone()))?;
+    match requested {
+        Some(requested) if requested != found => Err(KvsError::EngineMismatch {
+            requested: requested.to_string(),
+            found: found.to_string(),
+        }),
+        _ => Ok(found),
+    }
+}
+
+/// Records the engine in a directory which has no marker yet.
+///
+/// It is done once the engine has opened the directory, so a failed start
+/// leaves no marker behind.
+fn mark_directory(dir: &Path, marker: Option<EngineMarker>, engine: Engine) -> Result<()> {
+    if marker.is_none() {
+        EngineMarker::new(engine.to_string()).write(dir)?;
+    }
+    Ok(())
+}
+
+fn run_with_engine<E: KvEngine>(
+    engine: E,
+    addr: SocketAddr,
+    signals: ShutdownSignals,
+    logger: &Logger,
+) -> Result<()> {
+    {
+        let engine = engine.clone();
+        let logger = logger.clone();
+        signals.handle(move || {
+            info!(logger, "Shutting down");
+            match engine.flush() {
+                Ok(()) => exit(0),
+                Err(e) => {
+                    error!(logger, "{}", e);
+                    exit(1);
+                }
+            }
+        });
+    }
+    let server = KvsServer::new(engine, logger.clone());
+    server.run(addr)
+}
+
+/// The signals asking the server to shut down: SIGINT and SIGTERM.
+#[cfg(unix)]
+struct ShutdownSignals(libc::sigset_t);
+
+#[cfg(unix)]
+impl ShutdownSignals {
+    /// Blocks the signals in the current thread and the threads it spawns
+    /// from now on, so they are only taken by `ShutdownSignals::handle`.
+    fn block() -> ShutdownSignals {
+        unsafe {
+            let mut signals = std::mem::zeroed();
+            libc::sigemptyset(&mut signals);
+            libc::sigaddset(&mut signals, libc::SIGINT);
+            libc::sigaddset(&mut signals, libc::SIGTERM);
+            libc::pthread_sigmask(libc::SIG_BLOCK, &signals, std::ptr::null_mut());
+            ShutdownSignals(signals)
+        }
+    }
+
+    /// Runs `f` in a thread of its own once a signal arrives.
+    fn handle(self, f: impl FnOnce() + Send + 'static) {
+        std::thread::spawn(move || {
+            let mut signal = 0;
+            if unsafe { libc::sigwait(&self.0, &mut signal) } == 0 {
+                f();
+            }
+        });
+    }
+}
+
+/// Shutdown signals are not handled on other platforms.
+#[cfg(not(unix))]
+struct ShutdownSignals;
+
+#[cfg(not(unix))]
+impl ShutdownSignals {
+    fn block() -> ShutdownSignals {
+        ShutdownSignals
+    }
+
+    fn handle(self, _f: impl FnOnce() + Send + 'static) {}
+}
@@ -0,0 +1,274 @@
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::exit;
+use std::time::Instant;
+
+use clap::{Parser, Subcommand, ValueEnum};
+use kvs::{
+    EngineMarker, KvEngine, KvStore, KvsError, LsmEngine, Result, SledKvsEngine, WriteBatch,
+};
+
+/// Number of keys written to the new engine at once.
+const CONVERT_BATCH_SIZE: usize = 1000;
+
+/// Name of the lock file every engine keeps in its data directory.
+const LOCK_FILE: &str = "kvs.lock";
+
+/// Suffix of the directory a conversion copies the keys into.
+const CONVERT_SUFFIX: &str = "convert";
+
+#[derive(Parser, Debug)]
+#[command(name = "kvs-admin", version, about)]
+struct Opt {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Convert a data directory to another storage engine
+    ///
+    /// Every key is copied along with its expiry time into DIR.convert, which
+    /// replaces the old directory once the number of keys is verified. The old
+    /// directory is kept next to it, named after the old engine, as in DIR.sled.
+    ///
+    /// A directory without an engine marker is only converted if its files
+    /// are those of the FROM engine.
+    ///
+    /// The swap is two renames, of DIR to DIR.sled say, then of DIR.convert to
+    /// DIR. If it is interrupted in between, DIR is missing until the same
+    /// command is run again, which moves the complete copy in DIR.convert to DIR.
+    Convert {
+        /// The engine of the data directory
+        #[arg(long, value_enum, value_name = "ENGINE-NAME")]
+        from: Engine,
+        /// The engine to convert to
+        #[arg(long, value_enum, value_name = "ENGINE-NAME")]
+        to: Engine,
+        /// The data directory
+        dir: PathBuf,
+    },
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum Engine {
+    Kvs,
+    Sled,
+    Lsm,
+}
+
+impl std::fmt::Display for Engine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Engine::Kvs => write!(f, "kvs"),
+            Engine::Sled => write!(f, "sled"),
+            Engine::Lsm => write!(f, "lsm"),
+        }
+    }
+}
+
+fn main() {
+    let opt = Opt::parse();
+    if let Err(e) = run(opt) {
+        eprintln!("{}", e);
+        exit(1);
+    }
+}
+
+fn run(opt: Opt) -> Result<()> {
+    match opt.command {
+        Command::Convert { from, to, dir } => convert(from, to, &dir),
+    }
+}
+
+fn convert(from: Engine, to: Engine, dir: &Path) -> Result<()> {
+    if from == to {
+        return Err(KvsError::StringError(format!(
+            "Data directory already uses the {} engine",
+            to
+        )));
+    }
+    if let Some(marker) = finish_swap(dir)? {
+        println!(
+            "Finished an interrupted conversion of {} to the {} engine",
+            dir.display(),
+            marker.engine
+        );
+        return Ok(());
+    }
+    match EngineMarker::read(dir)? {
+        Some(marker) if marker.engine != from.to_string() => {
+            return Err(KvsError::EngineMismatch {
+                requested: from.to_string(),
+                found: marker.engine,
+            });
+        }
+        Some(_) => {}
+        // a directory written before the markers, or by something else.
+        None if !has_layout_of(dir, from)? => {
+            return Err(KvsError::StringError(format!(
+                "{} has no engine marker and does not hold {} data",
+                dir.display(),
+                from
+            )));
+        }
+        None => {}
+    }
+    let dir = dir.canonicalize()?;
+    let old_dir = sibling(&dir, from.to_string().as_str());
+    if old_dir.exists() {
+        return Err(KvsError::StringError(format!(
+            "{} is in the way of the old data, move it elsewhere first",
+            old_dir.display()
+        )));
+    }
+    // the source stays open, and so locked, until the directories are swapped.
+    match from {
+        Engine::Kvs => convert_from(KvStore::open(&dir)?, from, to, &dir),
+        Engine::Sled => convert_from(SledKvsEngine::open(&dir)?, from, to, &dir),
+        Engine::Lsm => convert_from(LsmEngine::open(&dir)?, from, to, &dir),
+    }
+}
+
+fn convert_from<S: KvEngine>(source: S, from: Engine, to: Engine, dir: &Path) -> Result<()> {
+    let new_dir = sibling(dir, CONVERT_SUFFIX);
+    if new_dir.exists() {
+        // the remains of a conversion interrupted before the swap.
+        fs::remove_dir_all(&new_dir)?;
+    }
+    let count = match to {
+        Engine::Kvs => copy(&source, KvStore::open(&new_dir)?)?,
+        Engine::Sled => copy(&source, SledKvsEngine::open(&new_dir)?)?,
+        Engine::Lsm => copy(&source, LsmEngine::open(&new_dir)?)?,
+    };
+    // the marker is written last, so it tells a complete copy.
+    EngineMarker::new(to.to_string()).write(&new_dir)?;
+
+    // the marker moves along with the data, so the directory switches
+    // engines at once. The swap is not atomic: a crash between the renames
+    // leaves no directory at `dir` and the complete copy at `new_dir`, until
+    // the next run moves it in place with `finish_swap`.
+    let old_dir = sibling(dir, from.to_string().as_str());
+    fs::rename(dir, &old_dir)?;
+    fs::rename(&new_dir, dir)?;
+    drop(source);
+
+    println!(
+        "Converted {} keys from {} to {}, the old data is kept in {}",
+        count,
+        from,
+        to,
+        old_dir.display()
+    );
+    Ok(())
+}
+
+/// Moves the complete copy of a conversion interrupted between the renames
+/// of the swap in place of the directory.
+///
+/// Returns the marker of the copy if there was such a conversion.
+fn finish_swap(dir: &Path) -> Result<Option<EngineMarker>> {
+    if dir.exists() {
+        return Ok(None);
+    }
+    let new_dir = sibling(dir, CONVERT_SUFFIX);
+    let marker = EngineMarker::read(&new_dir)?;
+    if marker.is_some() {
+        fs::rename(&new_dir, dir)?;
+    }
+    Ok(marker)
+}
+
+/// Tells whether every entry of a directory without a marker is a file the
+/// engine writes, so opening it with the engine leaves no foreign data behind.
+fn has_layout_of(dir: &Path, engine: Engine) -> Result<bool> {
+    for entry in fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = match name.to_str() {
+            Some(name) => name,
+            None => return Ok(false),
+        };
+        if name == LOCK_FILE {
+            continue;
+        }
+        let known = match engine {
+            Engine::Kvs => {
+                name.starts_with("kvs.header")
+                    || [".log", ".hint", ".hint.tmp"]
+                        .iter()
+                        .any(|ext| name.ends_with(ext))
+            }
+            Engine::Sled => ["conf", "db", "blobs"].contains(&name) || name.starts_with("snap."),
+            Engine::Lsm => {
+                name.starts_with("lsm.manifest")
+                    || [".sst", ".sst.tmp", ".wal"]
+                        .iter()
+                        .any(|ext| name.ends_with(ext))
+            }
+        };
+        if !known {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Copies every key of the source into the target, along with the time left
+/// until it expires, and checks the target holds as many keys as the source.
+///
+/// Returns the number of keys copied.
+fn copy<S: KvEngine, T: KvEngine>(source: &S, target: T) -> Result<u64> {
+    let mut count = 0;
+    let mut expiries = Vec::new();
+    let mut batch = WriteBatch::new();
+    for pair in source.scan(..)? {
+        let (key, _) = pair?;
+        // the key may have expired since the scan passed it.
+        let (value, ttl) = match source.get_bytes_with_ttl(key.clone())? {
+            Some(entry) => entry,
+            None => continue,
+        };
+        match ttl {
+            Some(ttl) => {
+                target.set_bytes_with_ttl(key, value, ttl)?;
+                expiries.push(Instant::now() + ttl);
+            }
+            None => {
+                batch.set(key, value);
+            }
+        }
+        count += 1;
+        if batch.len() == CONVERT_BATCH_SIZE {
+            target.write_batch(std::mem::take(&mut batch))?;
+        }
+    }
+    if !batch.is_empty() {
+        target.write_batch(batch)?;
+    }
+    target.flush()?;
+
+    let mut copied = 0;
+    for pair in target.scan(..)? {
+        pair?;
+        copied += 1;
+    }
+    // the keys which expired meanwhile are missing from the target.
+    let now = Instant::now();
+    let expired = expiries.iter().filter(|&&at| at <= now).count() as u64;
+    if copied > count || copied + expired < count {
+        return Err(KvsError::StringError(format!(
+            "Copied {} keys, but the new directory holds {}",
+            count, copied
+        )));
+    }
+    Ok(count)
+}
+
+/// Returns the path next to the directory with the suffix appended to its name.
+fn sibling(dir: &Path, suffix: &str) -> PathBuf {
+    let mut name = dir.file_name().map_or_else(OsString::new, OsString::from);
+    name.push(".");
+    name.push(suffix);
+    dir.with_file_name(name)
+}
@@ -0,0 +1,323 @@
+use std::collections::BTreeMap;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::de::{Deserializer, IoRead};
+
+use crate::protocol::{
+    BatchResponse, CompareAndSwapResponse, GetResponse, RemoveResponse, Request, SetResponse,
+    TransactionResponse, TxnRead, WatchResponse,
+};
+use crate::{KvsError, Result, WatchEvent, WriteBatch};
+
+/// Key value store client.
+///
+/// Like `KvEngine`, it works with byte strings and has string shorthands.
+pub struct KvsClient {
+    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl KvsClient {
+    /// Connects to `addr` to access `KvsServer`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let tcp_reader = TcpStream::connect(addr)?;
+        let tcp_writer = tcp_reader.try_clone()?;
+        Ok(KvsClient {
+            reader: Deserializer::from_reader(BufReader::new(tcp_reader)),
+            writer: BufWriter::new(tcp_writer),
+        })
+    }
+
+    /// Gets the string value of a given string key from the server.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Sets the value of a string key to a string in the server.
+    pub fn set(&mut self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string which expires after `ttl`.
+    pub fn set_with_ttl(&mut self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Removes a string key in the server.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Atomically replaces the value of a string key in the server if it
+    /// equals `expected`. `None` stands for a missing key.
+    ///
+    /// Returns `true` if the value was replaced.
+    pub fn compare_and_swap(
+        &mut self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets the value of a string key to a string in the server unless the
+    /// key exists.
+    ///
+    /// Returns `true` if the value was set.
+    pub fn set_if_absent(&mut self, key: String, value: String) -> Result<bool> {
+        self.compare_and_swap(key, None, Some(value))
+    }
+
+    /// Gets the value of a given key from the server.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.send(&Request::Get { key })?;
+        match GetResponse::deserialize(&mut self.reader)? {
+            GetResponse::Ok(value) => Ok(value),
+            GetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Sets the value of a key in the server.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.send_set(key, value, None)
+    }
+
+    /// Sets the value of a key which expires after `ttl` in the server.
+    pub fn set_bytes_with_ttl(
+        &mut self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+        ttl: Duration,
+    ) -> Result<()> {
+        self.send_set(key, value, Some(ttl))
+    }
+
+    /// Removes a key in the server.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        self.send(&Request::Remove { key })?;
+        match RemoveResponse::deserialize(&mut self.reader)? {
+            RemoveResponse::Ok(_) => Ok(()),
+            RemoveResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Applies all the writes of the batch atomically in the server.
+    pub fn write_batch(&mut self, batch: WriteBatch) -> Result<()> {
+        self.send(&Request::Batch { batch })?;
+        match BatchResponse::deserialize(&mut self.reader)? {
+            BatchResponse::Ok(_) => Ok(()),
+            BatchResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Atomically replaces the value of a key in the server if it equals
+    /// `expected`. `None` stands for a missing key.
+    ///
+    /// Returns `true` if the value was replaced.
+    pub fn compare_and_swap_bytes(
+        &mut self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.send(&Request::CompareAndSwap { key, expected, new })?;
+        match CompareAndSwapResponse::deserialize(&mut self.reader)? {
+            CompareAndSwapResponse::Ok(swapped) => Ok(swapped),
+            CompareAndSwapResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Applies all the writes of the batch atomically in the server if every
+    /// key of `reads` still holds the value it was read with.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed.
+    pub fn transact(
+        &mut self,
+        reads: Vec<(Vec<u8>, Option<Vec<u8>>)>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let reads = reads
+            .into_iter()
+            .map(|(key, value)| TxnRead { key, value })
+            .collect();
+        self.send(&Request::Transaction { reads, batch })?;
+        match TransactionResponse::deserialize(&mut self.reader)? {
+            TransactionResponse::Ok(_) => Ok(()),
+            TransactionResponse::Conflict => Err(KvsError::TxnConflict),
+            TransactionResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    /// Starts an optimistic transaction in the server.
+    ///
+    /// The transaction reads through this client and buffers its writes.
+    pub fn transaction(&mut self) -> RemoteTransaction<'_> {
+        RemoteTransaction {
+            client: self,
+            reads: BTreeMap::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Watches the writes to the keys starting with the prefix in the server.
+    ///
+    /// The connection is dedicated to the events from then on.
+    pub fn watch(mut self, prefix: Vec<u8>) -> Result<RemoteWatcher> {
+        self.send(&Request::Watch { prefix })?;
+        Ok(RemoteWatcher {
+            reader: self.reader,
+        })
+    }
+
+    fn send_set(&mut self, key: Vec<u8>, value: Vec<u8>, ttl: Option<Duration>) -> Result<()> {
+        self.send(&Request::Set { key, value, ttl })?;
+        match SetResponse::deserialize(&mut self.reader)? {
+            SetResponse::Ok(_) => Ok(()),
+            SetResponse::Err(msg) => Err(KvsError::StringError(msg)),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        serde_json::to_writer(&mut self.writer, request)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// The events of the keys watched through a `KvsClient`.
+///
+/// Iterating blocks until the next event. The iteration ends when the server
+/// closes the connection.
+pub struct RemoteWatcher {
+    reader: Deserializer<IoRead<BufReader<TcpStream>>>,
+}
+
+impl Iterator for RemoteWatcher {
+    type Item = Result<WatchEvent>;
+
+    fn next(&mut self) -> Option<Result<WatchEvent>> {
+        match WatchResponse::deserialize(&mut self.reader) {
+            Ok(WatchResponse::Event(event)) => Some(Ok(event)),
+            Ok(WatchResponse::Err(msg)) => Some(Err(KvsError::StringError(msg))),
+            Err(e) if e.is_eof() => None,
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+/// An optimistic transaction run through a `KvsClient`.
+///
+/// Every key read from the server is remembered along with its value. On
+/// commit the server applies the buffered writes atomically, and only if all
+/// the read keys still hold those values. Otherwise the commit fails with
+/// `KvsError::TxnConflict` and nothing is written.
+///
+/// Dropping a transaction without committing it discards its writes.
+pub struct RemoteTransaction<'a> {
+    client: &'a mut KvsClient,
+    // keys read from the server and the values they were read with
+    reads: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+    // buffered writes, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl RemoteTransaction<'_> {
+    /// Gets the string value of a given string key.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a given key, as written by the transaction or
+    /// else as read from the server.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.read(key),
+        }
+    }
+
+    /// Sets the value of a string key to a string on commit.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a key on commit.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a given string key on commit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Removes a given key on commit.
+    ///
+    /// A key only set by the transaction itself is just dropped from its writes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        if self.read(key.clone())?.is_some() {
+            self.writes.insert(key, None);
+        } else {
+            self.writes.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Reads a key from the server, regardless of the writes of the
+    /// transaction, and records the read.
+    ///
+    /// A key is read from the server only once, so repeated reads agree.
+    fn read(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if let Some(value) = self.reads.get(&key) {
+            return Ok(value.clone());
+        }
+        let value = self.client.get_bytes(key.clone())?;
+        self.reads.insert(key, value.clone());
+        Ok(value)
+    }
+
+    /// Commits the transaction in the server.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if a key read by the transaction
+    /// has changed in the meantime.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        self.client
+            .transact(self.reads.into_iter().collect(), batch)
+    }
+}
@@ -0,0 +1,138 @@
+//! Batches of writes applied atomically by `KvEngine::write_batch`.
+
+use std::collections::HashMap;
+use std::slice;
+use std::vec;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{KvsError, Result};
+
+/// A group of writes applied atomically by `KvEngine::write_batch`.
+///
+/// ```rust
+/// # use kvs::{KvEngine, KvStore, Result, WriteBatch};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// let mut batch = WriteBatch::new();
+/// batch
+///     .set("user:1:name", "alice")
+///     .set("user:1:email", "alice@example.com")
+///     .remove("user:1:pending");
+/// store.write_batch(batch)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WriteBatch {
+    ops: Vec<BatchOp>,
+}
+
+/// A single write of a `WriteBatch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum BatchOp {
+    /// Sets the value of a key.
+    Set {
+        /// The key.
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        /// The new value.
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+    },
+    /// Removes a key.
+    Remove {
+        /// The key.
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WriteBatch {
+    /// Creates an empty batch.
+    pub fn new() -> Self {
+        WriteBatch::default()
+    }
+
+    /// Adds setting the value of a key to the batch.
+    pub fn set(&mut self, key: impl Into<Vec<u8>>, value: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Set {
+            key: key.into(),
+            value: value.into(),
+        });
+        self
+    }
+
+    /// Adds removing a key to the batch.
+    ///
+    /// The key must exist when the batch is applied, or an earlier write of
+    /// the batch must set it.
+    pub fn remove(&mut self, key: impl Into<Vec<u8>>) -> &mut Self {
+        self.ops.push(BatchOp::Remove { key: key.into() });
+        self
+    }
+
+    /// Returns the number of writes in the batch.
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    /// Returns `true` if the batch holds no writes.
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    /// Iterates over the writes in the order they were added.
+    pub fn iter(&self) -> slice::Iter<'_, BatchOp> {
+        self.ops.iter()
+    }
+}
+
+impl IntoIterator for WriteBatch {
+    type Item = BatchOp;
+    type IntoIter = vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a WriteBatch {
+    type Item = &'a BatchOp;
+    type IntoIter = slice::Iter<'a, BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.iter()
+    }
+}
+
+/// Checks that the keys removed by the batch exist by then, either set by
+/// an earlier write of the batch or live in the engine as told by `live_value`.
+///
+/// # Errors
+///
+/// It returns `KvsError::KeyNotFound` for the first key removed while missing.
+pub(crate) fn check_removes<F>(batch: &WriteBatch, mut live_value: F) -> Result<()>
+where
+    F: FnMut(&[u8]) -> Result<bool>,
+{
+    let mut exists = HashMap::new();
+    for op in batch {
+        match op {
+            BatchOp::Set { key, .. } => {
+                exists.insert(key, true);
+            }
+            BatchOp::Remove { key } => {
+                let found = match exists.insert(key, false) {
+                    Some(found) => found,
+                    None => live_value(key)?,
+                };
+                if !found {
+                    return Err(KvsError::KeyNotFound);
+                }
+            }
+        }
+    }
+    Ok(())
+}
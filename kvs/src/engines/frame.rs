@@ -0,0 +1,149 @@
+//! Checksummed frames of the append-only logs, shared by the `KvStore` logs
+//! and the `LsmEngine` write-ahead logs.
+//!
+//! A frame is a little-endian `u32` payload length, a little-endian `u32`
+//! CRC32 checksum of the payload, a little-endian `u32` CRC32 checksum of the
+//! two preceding fields and the payload. The length is only trusted once the
+//! header checksum matches, so a damaged length is never taken for a torn write.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek};
+use std::ops::Range;
+use std::path::Path;
+
+use crate::{KvsError, Result};
+
+/// Length of the frame header: payload length, payload checksum and header checksum.
+pub(crate) const HEADER_LEN: usize = 12;
+
+/// Flag of a batch frame in the length field.
+pub(crate) const BATCH_FLAG: u32 = 1 << 31;
+
+/// Outcome of reading a single frame.
+pub(crate) enum Frame {
+    /// A complete frame with a matching checksum.
+    Valid(Vec<u8>),
+    /// A complete batch frame with a matching checksum.
+    Batch(Vec<u8>),
+    /// A frame with a mismatching checksum of the header or of the payload.
+    ///
+    /// The reader stops right after the header if the header is damaged.
+    Damaged,
+    /// The input ends in the middle of the header, or in the middle of the
+    /// payload of a frame with a valid header.
+    Incomplete,
+    /// The input ends right before the frame.
+    End,
+}
+
+/// Encodes the payload as a frame, with the flags set in its length field.
+pub(crate) fn encode_frame(payload: &[u8], flags: u32) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.extend_from_slice(&(payload.len() as u32 | flags).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(payload).to_le_bytes());
+    frame.extend_from_slice(&crc32fast::hash(&frame).to_le_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Reads and verifies the next frame.
+pub(crate) fn read_frame<R: Read>(reader: &mut R) -> Result<Frame> {
+    let mut header = [0; HEADER_LEN];
+    match read_full(reader, &mut header)? {
+        0 => return Ok(Frame::End),
+        HEADER_LEN => {}
+        _ => return Ok(Frame::Incomplete),
+    }
+    let header_checksum = u32::from_le_bytes([header[8], header[9], header[10], header[11]]);
+    if crc32fast::hash(&header[..8]) != header_checksum {
+        return Ok(Frame::Damaged);
+    }
+    let len = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let is_batch = len & BATCH_FLAG != 0;
+    let len = len & !BATCH_FLAG;
+    let checksum = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+
+    // a torn write may cut the payload short, so the buffer only grows as data is read.
+    let mut payload = Vec::new();
+    reader.take(u64::from(len)).read_to_end(&mut payload)?;
+    if payload.len() < len as usize {
+        Ok(Frame::Incomplete)
+    } else if crc32fast::hash(&payload) != checksum {
+        Ok(Frame::Damaged)
+    } else if is_batch {
+        Ok(Frame::Batch(payload))
+    } else {
+        Ok(Frame::Valid(payload))
+    }
+}
+
+/// Reads every frame of the log file in order and passes the complete ones,
+/// `Frame::Valid` or `Frame::Batch`, to `apply` along with their positions.
+/// `apply` returns `false` if it cannot decode the payload, in which case it
+/// must not have applied any of it.
+///
+/// A frame cut off by the end of the file is the remainder of an interrupted
+/// write, so the file is truncated right before it. That is a header cut
+/// short, a payload cut short under a valid header, or a damaged or
+/// undecodable frame with nothing after it.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if a frame followed by more data is damaged.
+pub(crate) fn replay<F>(path: &Path, generation: u64, mut apply: F) -> Result<()>
+where
+    F: FnMut(Frame, Range<u64>) -> bool,
+{
+    let file = File::open(path)?;
+    let file_len = file.metadata()?.len();
+    let mut reader = BufReader::new(file);
+    let mut pos = 0;
+    loop {
+        let frame = read_frame(&mut reader)?;
+        let new_pos = reader.stream_position()?;
+        let applied = match frame {
+            Frame::Valid(_) | Frame::Batch(_) => apply(frame, pos..new_pos),
+            Frame::Damaged => false,
+            Frame::Incomplete => {
+                truncate(path, pos)?;
+                break;
+            }
+            Frame::End => break,
+        };
+        if !applied {
+            // a torn write may leave a complete but garbled last frame.
+            if new_pos == file_len {
+                truncate(path, pos)?;
+                break;
+            }
+            return Err(KvsError::Corruption {
+                generation,
+                offset: pos,
+            });
+        }
+        pos = new_pos;
+    }
+    Ok(())
+}
+
+/// Reads until the buffer is full or the input ends, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]) {
+            Ok(0) => break,
+            Ok(len) => read += len,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(read)
+}
+
+/// Cuts the file off at the given length.
+fn truncate(path: &Path, len: u64) -> Result<()> {
+    let file = OpenOptions::new().write(true).open(path)?;
+    file.set_len(len)?;
+    file.sync_all()?;
+    Ok(())
+}
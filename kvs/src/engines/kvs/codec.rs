@@ -0,0 +1,358 @@
+//! Encodings of the `KvStore` log records.
+
+use std::convert::TryInto;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::log::Command;
+use crate::{KvsError, Result};
+
+/// Name of the file recording the codec of a data directory.
+const HEADER_FILE: &str = "kvs.header";
+
+/// Encoding of the commands stored in the `KvStore` log.
+///
+/// The codec is chosen when a data directory is created and recorded in its
+/// header file. Opening an existing directory always uses the recorded codec.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    /// Human-readable json, handy for debugging.
+    Json,
+    /// BSON documents.
+    Bson,
+    /// Compact length-prefixed binary encoding.
+    Binary,
+}
+
+impl Codec {
+    /// Returns the implementation of the codec.
+    pub(super) fn command_codec(self) -> &'static dyn CommandCodec {
+        match self {
+            Codec::Json => &JsonCodec,
+            Codec::Bson => &BsonCodec,
+            Codec::Binary => &BinaryCodec,
+        }
+    }
+}
+
+impl fmt::Display for Codec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Codec::Json => write!(f, "json"),
+            Codec::Bson => write!(f, "bson"),
+            Codec::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl FromStr for Codec {
+    type Err = KvsError;
+
+    fn from_str(s: &str) -> Result<Codec> {
+        match s {
+            "json" => Ok(Codec::Json),
+            "bson" => Ok(Codec::Bson),
+            "binary" => Ok(Codec::Binary),
+            _ => Err(KvsError::UnknownCodec(s.to_owned())),
+        }
+    }
+}
+
+/// Converts commands to the payloads of the log frames and back.
+pub(super) trait CommandCodec: Send + std::marker::Sync {
+    /// Serializes the command.
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>>;
+
+    /// Deserializes a command from the payload.
+    fn decode(&self, payload: &[u8]) -> Result<Command>;
+}
+
+struct JsonCodec;
+
+impl CommandCodec for JsonCodec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        Ok(serde_json::to_vec(cmd)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Command> {
+        Ok(serde_json::from_slice(payload)?)
+    }
+}
+
+struct BsonCodec;
+
+impl CommandCodec for BsonCodec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        Ok(bson::to_vec(cmd)?)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Command> {
+        Ok(bson::from_slice(payload)?)
+    }
+}
+
+/// Encodes a command as a tag byte followed by its fields, every field being
+/// a little-endian `u32` length and the bytes.
+///
+/// The tag tells the kind of the command and flags the optional trailing
+/// numbers: the expiry time, the sequence number and the time of the write,
+/// all little-endian `u64`.
+struct BinaryCodec;
+
+const SET_TAG: u8 = 0;
+const REMOVE_TAG: u8 = 1;
+const KIND_MASK: u8 = 1;
+const EXPIRY_FLAG: u8 = 2;
+const SEQ_FLAG: u8 = 4;
+const TIME_FLAG: u8 = 8;
+
+impl CommandCodec for BinaryCodec {
+    fn encode(&self, cmd: &Command) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match cmd {
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                seq,
+                written_at,
+            } => {
+                let mut tag = SET_TAG;
+                if expires_at.is_some() {
+                    tag |= EXPIRY_FLAG;
+                }
+                buf.push(tag);
+                put_field(&mut buf, key);
+                put_field(&mut buf, value);
+                if let Some(expires_at) = expires_at {
+                    buf.extend_from_slice(&expires_at.to_le_bytes());
+                }
+                put_numbers(&mut buf, *seq, *written_at);
+            }
+            Command::Remove {
+                key,
+                seq,
+                written_at,
+            } => {
+                buf.push(REMOVE_TAG);
+                put_field(&mut buf, key);
+                put_numbers(&mut buf, *seq, *written_at);
+            }
+        }
+        Ok(buf)
+    }
+
+    fn decode(&self, payload: &[u8]) -> Result<Command> {
+        let (&tag, mut rest) = payload.split_first().ok_or_else(invalid_payload)?;
+        if tag & !(KIND_MASK | EXPIRY_FLAG | SEQ_FLAG | TIME_FLAG) != 0 {
+            return Err(invalid_payload());
+        }
+        let key = take_field(&mut rest)?.to_vec();
+        let cmd = if tag & KIND_MASK == SET_TAG {
+            let value = take_field(&mut rest)?.to_vec();
+            let expires_at = if tag & EXPIRY_FLAG != 0 {
+                Some(take_u64(&mut rest)?)
+            } else {
+                None
+            };
+            let (seq, written_at) = take_numbers(&mut rest, tag)?;
+            Command::Set {
+                key,
+                value,
+                expires_at,
+                seq,
+                written_at,
+            }
+        } else {
+            if tag & EXPIRY_FLAG != 0 {
+                return Err(invalid_payload());
+            }
+            let (seq, written_at) = take_numbers(&mut rest, tag)?;
+            Command::Remove {
+                key,
+                seq,
+                written_at,
+            }
+        };
+        if rest.is_empty() {
+            Ok(cmd)
+        } else {
+            Err(invalid_payload())
+        }
+    }
+}
+
+/// Appends the sequence number and the time of the write, flagging
+/// the non-zero ones in the tag at the start of the buffer.
+fn put_numbers(buf: &mut Vec<u8>, seq: u64, written_at: u64) {
+    if seq != 0 {
+        buf[0] |= SEQ_FLAG;
+        buf.extend_from_slice(&seq.to_le_bytes());
+    }
+    if written_at != 0 {
+        buf[0] |= TIME_FLAG;
+        buf.extend_from_slice(&written_at.to_le_bytes());
+    }
+}
+
+/// Takes the sequence number and the time of the write flagged in the tag.
+fn take_numbers(buf: &mut &[u8], tag: u8) -> Result<(u64, u64)> {
+    let seq = if tag & SEQ_FLAG != 0 {
+        take_u64(buf)?
+    } else {
+        0
+    };
+    let written_at = if tag & TIME_FLAG != 0 {
+        take_u64(buf)?
+    } else {
+        0
+    };
+    Ok((seq, written_at))
+}
+
+fn put_field(buf: &mut Vec<u8>, field: &[u8]) {
+    buf.extend_from_slice(&(field.len() as u32).to_le_bytes());
+    buf.extend_from_slice(field);
+}
+
+fn take_field<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    if buf.len() < 4 {
+        return Err(invalid_payload());
+    }
+    let (len, rest) = buf.split_at(4);
+    let len = u32::from_le_bytes(len.try_into().unwrap()) as usize;
+    if rest.len() < len {
+        return Err(invalid_payload());
+    }
+    let (field, rest) = rest.split_at(len);
+    *buf = rest;
+    Ok(field)
+}
+
+fn take_u64(buf: &mut &[u8]) -> Result<u64> {
+    if buf.len() < 8 {
+        return Err(invalid_payload());
+    }
+    let (n, rest) = buf.split_at(8);
+    *buf = rest;
+    Ok(u64::from_le_bytes(n.try_into().unwrap()))
+}
+
+fn invalid_payload() -> KvsError {
+    io::Error::new(io::ErrorKind::InvalidData, "invalid binary command").into()
+}
+
+/// Serde helpers for the numbers of the commands.
+///
+/// BSON has no unsigned integers, so a number above `i64::MAX` is serialized
+/// as its little-endian bytes, and any other one as a plain integer. Both
+/// forms are accepted when deserializing.
+pub(super) mod number {
+    use std::convert::TryInto;
+    use std::fmt;
+
+    use serde::de::{self, SeqAccess, Visitor};
+    use serde::{Deserializer, Serializer};
+
+    pub(crate) fn serialize<S: Serializer>(n: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        if *n > i64::MAX as u64 {
+            serializer.serialize_bytes(&n.to_le_bytes())
+        } else {
+            serializer.serialize_u64(*n)
+        }
+    }
+
+    pub(crate) fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        deserializer.deserialize_any(NumberVisitor)
+    }
+
+    struct NumberVisitor;
+
+    impl<'de> Visitor<'de> for NumberVisitor {
+        type Value = u64;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("an unsigned integer or its 8 little-endian bytes")
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<u64, E> {
+            Ok(v)
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<u64, E> {
+            v.try_into()
+                .map_err(|_| E::invalid_value(de::Unexpected::Signed(v), &self))
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<u64, E> {
+            let bytes = v
+                .try_into()
+                .map_err(|_| E::invalid_length(v.len(), &self))?;
+            Ok(u64::from_le_bytes(bytes))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<u64, A::Error> {
+            let mut bytes = Vec::with_capacity(8);
+            while let Some(byte) = seq.next_element()? {
+                bytes.push(byte);
+            }
+            self.visit_bytes(&bytes)
+        }
+    }
+
+    /// The same for optional numbers.
+    pub(crate) mod option {
+        use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+        struct NumberRef<'a>(&'a u64);
+
+        impl Serialize for NumberRef<'_> {
+            fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                super::serialize(self.0, serializer)
+            }
+        }
+
+        #[derive(Deserialize)]
+        struct Number(#[serde(with = "super")] u64);
+
+        pub(crate) fn serialize<S: Serializer>(
+            n: &Option<u64>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match n {
+                Some(n) => serializer.serialize_some(&NumberRef(n)),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub(crate) fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<u64>, D::Error> {
+            Ok(Option::<Number>::deserialize(deserializer)?.map(|n| n.0))
+        }
+    }
+}
+
+/// Returns the codec recorded in the header of the data directory, if any.
+pub(super) fn read_header(dir: &Path) -> Result<Option<Codec>> {
+    match fs::read_to_string(dir.join(HEADER_FILE)) {
+        Ok(header) => Ok(Some(header.trim().parse()?)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Records the codec in the header of the data directory.
+///
+/// The file is written under a temporary name and renamed, so a crash never
+/// leaves a partial header behind.
+pub(super) fn write_header(dir: &Path, codec: Codec) -> Result<()> {
+    let tmp_path = dir.join(format!("{}.tmp", HEADER_FILE));
+    fs::write(&tmp_path, format!("{}\n", codec))?;
+    fs::File::open(&tmp_path)?.sync_all()?;
+    fs::rename(tmp_path, dir.join(HEADER_FILE))?;
+    Ok(())
+}
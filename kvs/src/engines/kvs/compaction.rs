@@ -0,0 +1,233 @@
+//! Background compaction of the `KvStore` log generations and sweeping of
+//! the expired keys.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use super::hint::{hint_path, write_hint};
+use super::index::{Index, Version};
+use super::log::{log_path, new_log_file, sorted_gen_list, CommandPos, GenStats, LogReader};
+use crate::engines::now_millis;
+use crate::{KvsError, Result};
+
+/// A request to compact every generation older than `compaction_gen`.
+struct CompactionJob {
+    compaction_gen: u64,
+    // notified once the job is finished
+    done: Sender<Result<()>>,
+}
+
+/// State shared between the `KvStore` and its compaction thread.
+pub(super) struct Shared {
+    pub(super) path: Arc<PathBuf>,
+    pub(super) index: Arc<Index>,
+    pub(super) stats: Arc<Mutex<BTreeMap<u64, GenStats>>>,
+    pub(super) safe_point: Arc<AtomicU64>,
+}
+
+/// Handle to the background compaction thread.
+///
+/// Jobs are processed one by one in the order they are scheduled. Dropping
+/// the handle waits for the scheduled jobs to finish. Between the jobs, the
+/// thread periodically sweeps the expired keys and the versions no snapshot
+/// reads anymore out of the index.
+pub(super) struct Compactor {
+    sender: Option<Sender<CompactionJob>>,
+    handle: Option<JoinHandle<()>>,
+    in_progress: Arc<AtomicBool>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread reading the logs with the given reader.
+    pub(super) fn spawn(
+        shared: Shared,
+        reader: LogReader,
+        sweep_interval: Duration,
+    ) -> Result<Compactor> {
+        let (sender, receiver) = mpsc::channel();
+        let in_progress = Arc::new(AtomicBool::new(false));
+        let thread_in_progress = Arc::clone(&in_progress);
+        let handle = thread::Builder::new()
+            .name("kvs-compaction".to_owned())
+            .spawn(move || run(shared, reader, receiver, thread_in_progress, sweep_interval))?;
+
+        Ok(Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+            in_progress,
+        })
+    }
+
+    /// Returns `true` if a scheduled compaction has not finished yet.
+    pub(super) fn in_progress(&self) -> bool {
+        self.in_progress.load(Ordering::SeqCst)
+    }
+
+    /// Schedules a compaction of every generation older than `compaction_gen`.
+    ///
+    /// The returned handle can be used to wait for the compaction result.
+    pub(super) fn schedule(&self, compaction_gen: u64) -> Result<PendingCompaction> {
+        let (done, receiver) = mpsc::channel();
+        self.in_progress.store(true, Ordering::SeqCst);
+        self.sender
+            .as_ref()
+            .expect("compaction thread is stopped")
+            .send(CompactionJob {
+                compaction_gen,
+                done,
+            })
+            .map_err(|_| compaction_thread_gone())?;
+        Ok(PendingCompaction(receiver))
+    }
+}
+
+/// A scheduled compaction.
+pub(super) struct PendingCompaction(Receiver<Result<()>>);
+
+impl PendingCompaction {
+    /// Blocks until the compaction is finished and returns its result.
+    pub(super) fn wait(self) -> Result<()> {
+        self.0
+            .recv()
+            .unwrap_or_else(|_| Err(compaction_thread_gone()))
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel stops the thread after the pending jobs.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn remove_if_exists(path: &Path) -> Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
+
+fn compaction_thread_gone() -> KvsError {
+    io::Error::other("compaction thread has exited").into()
+}
+
+fn run(
+    shared: Shared,
+    mut reader: LogReader,
+    receiver: Receiver<CompactionJob>,
+    in_progress: Arc<AtomicBool>,
+    sweep_interval: Duration,
+) {
+    loop {
+        let job = match receiver.recv_timeout(sweep_interval) {
+            Ok(job) => job,
+            Err(RecvTimeoutError::Timeout) => {
+                sweep(&shared);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        // A failed compaction leaves the old generations untouched,
+        // so they are simply compacted again by a later job.
+        let res = compact(&shared, &mut reader, job.compaction_gen);
+        in_progress.store(false, Ordering::SeqCst);
+        // nobody may be waiting for an automatically scheduled compaction.
+        let _ = job.done.send(res);
+    }
+}
+
+/// Removes the expired keys and the unused versions from the index.
+///
+/// Expired records need no tombstone: they carry the expiry time, so they
+/// stay expired when the log is replayed.
+fn sweep(shared: &Shared) {
+    let mut index = shared.index.write();
+    index.prune(now_millis(), &mut shared.stats.lock().unwrap());
+}
+
+/// Copies the versions in the index from every generation older than
+/// `compaction_gen` into the `compaction_gen` log along with its hint file,
+/// then atomically switches the index over and deletes the old log files.
+///
+/// Writers keep appending to newer generations meanwhile. Versions that were
+/// dropped during the copy are not switched over, and their copies are
+/// accounted as stale. Expired keys and unused versions are swept out of the
+/// index beforehand, so they are not copied.
+fn compact(shared: &Shared, reader: &mut LogReader, compaction_gen: u64) -> Result<()> {
+    sweep(shared);
+    let mut entries: Vec<(Vec<u8>, Version)> = Vec::new();
+    for entry in shared.index.iter() {
+        entry.value().with(|versions| {
+            entries.extend(
+                versions
+                    .iter()
+                    .filter(|version| version.pos.gen < compaction_gen)
+                    .map(|version| (entry.key().clone(), *version)),
+            )
+        });
+    }
+
+    let mut writer = new_log_file(&shared.path, compaction_gen)?;
+    let mut moved = Vec::with_capacity(entries.len());
+    for (key, old) in entries {
+        let old_pos = old.pos;
+        let pos = writer.pos;
+        reader.read_and(old_pos, |mut entry_reader| {
+            io::copy(&mut entry_reader, &mut writer)?;
+            Ok(())
+        })?;
+        let new = Version {
+            pos: CommandPos {
+                expires_at: old_pos.expires_at,
+                ..(compaction_gen, pos..writer.pos).into()
+            },
+            ..old
+        };
+        moved.push((key, old, new));
+    }
+    // the old logs are deleted below, so the copies must be durable first.
+    writer.sync()?;
+    write_hint(
+        &shared.path,
+        compaction_gen,
+        moved.iter().map(|(key, _, new)| (key, new)),
+    )?;
+
+    {
+        let mut index = shared.index.write();
+        let mut stats = shared.stats.lock().unwrap();
+        let mut gen_stats = GenStats::default();
+        for (key, old, new) in moved {
+            // the "remove" commands are kept for snapshots only, so they count as stale.
+            if index.relocate(&key, &old, new.pos) && !new.removed {
+                gen_stats.live += new.pos.len;
+            } else {
+                gen_stats.stale += new.pos.len;
+            }
+        }
+        stats.retain(|&gen, _| gen > compaction_gen);
+        stats.insert(compaction_gen, gen_stats);
+        // readers which fail to read a deleted log look the key up again.
+        shared.safe_point.store(compaction_gen, Ordering::SeqCst);
+    }
+
+    for gen in sorted_gen_list(&shared.path)? {
+        if gen < compaction_gen {
+            fs::remove_file(log_path(&shared.path, gen))?;
+            remove_if_exists(&hint_path(&shared.path, gen))?;
+        }
+    }
+    reader.close_stale_handles();
+
+    Ok(())
+}
@@ -0,0 +1,107 @@
+//! Hint files of the compacted `KvStore` logs.
+//!
+//! A hint file lists the location of every entry of a compacted log, so the
+//! index can be rebuilt at startup without reading the values.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::index::{IndexWriter, Version};
+use super::log::{CommandPos, GenStats};
+use crate::Result;
+
+/// Location of a single entry of a compacted log.
+#[derive(Serialize, Deserialize, Debug)]
+struct Hint {
+    #[serde(with = "crate::bytes")]
+    key: Vec<u8>,
+    gen: u64,
+    pos: u64,
+    len: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+    #[serde(default)]
+    seq: u64,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    removed: bool,
+    #[serde(default)]
+    written_at: u64,
+}
+
+/// Writes the hint file of the `gen` log.
+///
+/// The file is written under a temporary name and renamed once it is
+/// complete, so a crash never leaves a truncated hint file behind.
+pub(super) fn write_hint<'a>(
+    path: &Path,
+    gen: u64,
+    entries: impl Iterator<Item = (&'a Vec<u8>, &'a Version)>,
+) -> Result<()> {
+    let tmp_path = path.join(format!("{}.hint.tmp", gen));
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for (key, version) in entries {
+        let hint = Hint {
+            key: key.clone(),
+            gen: version.pos.gen,
+            pos: version.pos.pos,
+            len: version.pos.len,
+            expires_at: version.pos.expires_at,
+            seq: version.seq,
+            removed: version.removed,
+            written_at: version.written_at,
+        };
+        serde_json::to_writer(&mut writer, &hint)?;
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp_path, hint_path(path, gen))?;
+    Ok(())
+}
+
+/// Loads the hint file of the `gen` log into the index.
+///
+/// Returns `false` if there is no usable hint file, in which case
+/// the log itself has to be replayed.
+pub(super) fn load_hint(
+    path: &Path,
+    gen: u64,
+    index: &mut IndexWriter,
+    stats: &mut BTreeMap<u64, GenStats>,
+) -> Result<bool> {
+    let file = match File::open(hint_path(path, gen)) {
+        Ok(file) => file,
+        Err(_) => return Ok(false),
+    };
+    let hints: serde_json::Result<Vec<Hint>> = Deserializer::from_reader(BufReader::new(file))
+        .into_iter::<Hint>()
+        .collect();
+    let hints = match hints {
+        Ok(hints) => hints,
+        Err(_) => return Ok(false),
+    };
+
+    for hint in hints {
+        let version = Version {
+            seq: hint.seq,
+            pos: CommandPos {
+                gen: hint.gen,
+                pos: hint.pos,
+                len: hint.len,
+                expires_at: hint.expires_at,
+            },
+            removed: hint.removed,
+            written_at: hint.written_at,
+        };
+        index.apply(hint.key, version, stats);
+    }
+    Ok(true)
+}
+
+pub(super) fn hint_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.hint", gen))
+}
@@ -0,0 +1,386 @@
+//! In-memory index of the `KvStore`: the versions of every key.
+//!
+//! Every write gets a sequence number, shared by all the writes of a batch,
+//! which is the version it creates. A key keeps its older versions as long as
+//! the retention policy asks for them or a live snapshot still reads them. A
+//! removed key stays as well while a snapshot older than the removal lives.
+//!
+//! The keys live in a lock-free skip list, so readers never wait. A write
+//! swaps the versions of its key as a whole, the old ones being freed once no
+//! reader may still see them, and the writes themselves are serialized by the
+//! `IndexWriter` lock.
+
+use std::collections::{btree_map, BTreeMap};
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crossbeam_epoch::{self as epoch, Atomic, Owned};
+use crossbeam_skiplist::map::{Iter, Range};
+use crossbeam_skiplist::SkipMap;
+
+use super::log::{mark_live, mark_stale, Command, CommandPos, GenStats};
+use super::options::Retention;
+use crate::engines::now_millis;
+
+/// A retained version of a key, as listed by `KvStore::history`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyVersion {
+    /// The version number, the same for all the writes of a batch.
+    pub version: u64,
+    /// When the version was written.
+    pub written_at: SystemTime,
+    /// The value written, `None` for a removal.
+    pub value: Option<Vec<u8>>,
+}
+
+/// A version of a key: the position of the command which set or removed it.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) struct Version {
+    pub(super) seq: u64,
+    pub(super) pos: CommandPos,
+    pub(super) removed: bool,
+    // milliseconds since the unix epoch
+    pub(super) written_at: u64,
+}
+
+impl Version {
+    /// Returns `true` if the version holds a value at `now`.
+    pub(super) fn is_visible(&self, now: u64) -> bool {
+        !self.removed && !self.pos.is_expired(now)
+    }
+}
+
+/// The retained versions of a key.
+#[derive(Clone, Debug)]
+pub(super) struct Versions {
+    latest: Version,
+    // older versions, oldest first
+    older: Vec<Version>,
+}
+
+impl Versions {
+    /// Returns the latest version written at or before `seq`.
+    pub(super) fn at(&self, seq: u64) -> Option<&Version> {
+        self.iter().rev().find(|version| version.seq <= seq)
+    }
+
+    /// Iterates over the versions, oldest first.
+    pub(super) fn iter(&self) -> impl DoubleEndedIterator<Item = &Version> {
+        self.older.iter().chain(Some(&self.latest))
+    }
+
+    fn iter_mut(&mut self) -> impl Iterator<Item = &mut Version> {
+        self.older.iter_mut().chain(Some(&mut self.latest))
+    }
+
+    /// Drops the older versions neither the retention policy nor
+    /// a snapshot asks for anymore.
+    fn prune(
+        &mut self,
+        snapshots: &BTreeMap<u64, usize>,
+        retention: &Retention,
+        now: u64,
+        stats: &mut BTreeMap<u64, GenStats>,
+    ) {
+        if self.older.is_empty() {
+            return;
+        }
+        let retained_for = retention.duration.as_millis() as u64;
+        let mut kept = Vec::new();
+        let mut newer = self.latest;
+        // walk from the newest to the oldest: a version is read by the
+        // snapshots taken before the next version was written.
+        for (age, version) in self.older.drain(..).rev().enumerate() {
+            let retained = age + 1 < retention.versions
+                || newer.written_at.saturating_add(retained_for) > now
+                || snapshots.range(version.seq..newer.seq).next().is_some();
+            if retained {
+                kept.push(version);
+            } else if !version.removed {
+                mark_stale(stats, &version.pos);
+            }
+            newer = version;
+        }
+        kept.reverse();
+        self.older = kept;
+    }
+}
+
+/// The versions of a key, swapped atomically by the writes.
+///
+/// Replacing the entry of the skip list instead would briefly hide the key
+/// from readers.
+pub(super) struct VersionsCell(Atomic<Versions>);
+
+impl VersionsCell {
+    fn new(versions: Versions) -> VersionsCell {
+        VersionsCell(Atomic::new(versions))
+    }
+
+    /// Passes the current versions to `f`.
+    pub(super) fn with<T>(&self, f: impl FnOnce(&Versions) -> T) -> T {
+        let guard = epoch::pin();
+        let versions = self.0.load(Ordering::Acquire, &guard);
+        // the cell is never empty, and the versions swapped out are only
+        // freed once every reader pinned before the swap is done.
+        f(unsafe { versions.deref() })
+    }
+
+    /// Returns a copy of the current versions.
+    fn load(&self) -> Versions {
+        self.with(Versions::clone)
+    }
+
+    /// Replaces the versions.
+    fn store(&self, versions: Versions) {
+        let guard = epoch::pin();
+        let old = self.0.swap(Owned::new(versions), Ordering::AcqRel, &guard);
+        unsafe { guard.defer_destroy(old) };
+    }
+}
+
+impl Drop for VersionsCell {
+    fn drop(&mut self) {
+        // the skip list drops a value once no reader holds its entry.
+        unsafe {
+            drop(
+                self.0
+                    .load(Ordering::Relaxed, epoch::unprotected())
+                    .into_owned(),
+            )
+        }
+    }
+}
+
+/// The versions of all the keys along with the live snapshots.
+pub(super) struct Index {
+    keys: SkipMap<Vec<u8>, VersionsCell>,
+    retention: Retention,
+    // sequence number of the latest applied write
+    last_seq: AtomicU64,
+    // sequence numbers of the live snapshots and how many there are of each,
+    // also serializing the writes to the index
+    snapshots: Mutex<BTreeMap<u64, usize>>,
+}
+
+impl Index {
+    /// Creates an empty index keeping the older versions as asked by `retention`.
+    pub(super) fn new(retention: Retention) -> Index {
+        Index {
+            keys: SkipMap::new(),
+            retention,
+            last_seq: AtomicU64::new(0),
+            snapshots: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Returns the sequence number of the latest applied write.
+    pub(super) fn last_seq(&self) -> u64 {
+        self.last_seq.load(Ordering::SeqCst)
+    }
+
+    /// Returns the sequence number of the latest write to a key still
+    /// in the index.
+    pub(super) fn latest_seq(&self, key: &[u8]) -> Option<u64> {
+        self.keys
+            .get(key)
+            .map(|entry| entry.value().with(|versions| versions.latest.seq))
+    }
+
+    /// Returns the retained versions of a key.
+    pub(super) fn get(&self, key: &[u8]) -> Option<Versions> {
+        self.keys.get(key).map(|entry| entry.value().load())
+    }
+
+    /// Returns the version of a key holding a value for a read at `seq`.
+    pub(super) fn lookup(&self, key: &[u8], seq: u64, now: u64) -> Option<Version> {
+        self.keys
+            .get(key)
+            .and_then(|entry| entry.value().with(|versions| versions.at(seq).copied()))
+            .filter(|version| version.is_visible(now))
+    }
+
+    /// Iterates over the keys within the range in sorted order.
+    pub(super) fn range<R: RangeBounds<Vec<u8>>>(
+        &self,
+        range: R,
+    ) -> Range<'_, Vec<u8>, R, Vec<u8>, VersionsCell> {
+        self.keys.range(range)
+    }
+
+    /// Iterates over all the keys in sorted order.
+    pub(super) fn iter(&self) -> Iter<'_, Vec<u8>, VersionsCell> {
+        self.keys.iter()
+    }
+
+    /// Locks the index for writing.
+    ///
+    /// Readers are not blocked, they see every key either before
+    /// or after a write.
+    pub(super) fn write(&self) -> IndexWriter<'_> {
+        IndexWriter {
+            index: self,
+            snapshots: self.snapshots.lock().unwrap(),
+        }
+    }
+}
+
+/// Exclusive access to the writes of the `Index`.
+pub(super) struct IndexWriter<'a> {
+    index: &'a Index,
+    snapshots: MutexGuard<'a, BTreeMap<u64, usize>>,
+}
+
+impl IndexWriter<'_> {
+    /// Applies a command written at `cmd_pos`.
+    pub(super) fn apply_command(
+        &mut self,
+        cmd: Command,
+        cmd_pos: CommandPos,
+        stats: &mut BTreeMap<u64, GenStats>,
+    ) {
+        let seq = cmd.seq();
+        let written_at = cmd.written_at();
+        let (key, removed) = match cmd {
+            Command::Set { key, .. } => (key, false),
+            Command::Remove { key, .. } => (key, true),
+        };
+        self.apply(
+            key,
+            Version {
+                seq,
+                pos: cmd_pos,
+                removed,
+                written_at,
+            },
+            stats,
+        );
+    }
+
+    /// Makes the version the latest one of its key.
+    ///
+    /// Versions must be applied in the order of their sequence numbers.
+    pub(super) fn apply(
+        &mut self,
+        key: Vec<u8>,
+        mut version: Version,
+        stats: &mut BTreeMap<u64, GenStats>,
+    ) {
+        let index = self.index;
+        // commands written before the sequence numbers were introduced
+        // are numbered in the order they are applied.
+        let last_seq = index.last_seq();
+        if version.seq == 0 {
+            version.seq = last_seq + 1;
+        }
+        // the sequence number is published once the key is in place, so
+        // a read at the latest version sees the write.
+        let seq = version.seq;
+
+        if version.removed {
+            // the "remove" command itself can be deleted in the next compaction.
+            stats.entry(version.pos.gen).or_default().stale += version.pos.len;
+        } else {
+            mark_live(stats, &version.pos);
+        }
+
+        match index.keys.get(&key) {
+            Some(entry) => {
+                let mut versions = entry.value().load();
+                let previous = std::mem::replace(&mut versions.latest, version);
+                versions.older.push(previous);
+                versions.prune(&self.snapshots, &index.retention, now_millis(), stats);
+                if versions.older.is_empty()
+                    && versions.latest.removed
+                    && !self.is_pinned_before(versions.latest.seq)
+                {
+                    entry.remove();
+                } else {
+                    entry.value().store(versions);
+                }
+            }
+            None => {
+                if !version.removed {
+                    let versions = Versions {
+                        latest: version,
+                        older: Vec::new(),
+                    };
+                    index.keys.insert(key, VersionsCell::new(versions));
+                }
+            }
+        }
+        index.last_seq.store(last_seq.max(seq), Ordering::SeqCst);
+    }
+
+    /// Drops the versions no one asks for anymore and the keys which
+    /// are removed or expired for everyone.
+    pub(super) fn prune(&mut self, now: u64, stats: &mut BTreeMap<u64, GenStats>) {
+        let index = self.index;
+        for entry in index.keys.iter() {
+            let mut versions = entry.value().load();
+            let older_len = versions.older.len();
+            versions.prune(&self.snapshots, &index.retention, now, stats);
+            if !versions.older.is_empty()
+                || versions.latest.is_visible(now)
+                || self.is_pinned_before(versions.latest.seq)
+            {
+                if versions.older.len() != older_len {
+                    entry.value().store(versions);
+                }
+                continue;
+            }
+            if !versions.latest.removed {
+                mark_stale(stats, &versions.latest.pos);
+            }
+            entry.remove();
+        }
+    }
+
+    /// Returns `true` if a live snapshot was taken before the write `seq`.
+    ///
+    /// The latest version of a key is kept for such a snapshot even once it
+    /// holds no value, so a transaction reading the key from the snapshot
+    /// notices that it was written.
+    fn is_pinned_before(&self, seq: u64) -> bool {
+        self.snapshots.range(..seq).next().is_some()
+    }
+
+    /// Moves a version to the new position if the key still has it.
+    ///
+    /// Returns `false` if the version has been dropped meanwhile.
+    pub(super) fn relocate(&mut self, key: &[u8], old: &Version, new_pos: CommandPos) -> bool {
+        let entry = match self.index.keys.get(key) {
+            Some(entry) => entry,
+            None => return false,
+        };
+        let mut versions = entry.value().load();
+        match versions.iter_mut().find(|version| *version == old) {
+            Some(version) => version.pos = new_pos,
+            None => return false,
+        }
+        entry.value().store(versions);
+        true
+    }
+
+    /// Registers a snapshot of the latest write, so the versions it reads
+    /// are kept until `unpin` is called.
+    ///
+    /// Returns the sequence number of the snapshot.
+    pub(super) fn pin(&mut self) -> u64 {
+        let seq = self.index.last_seq();
+        *self.snapshots.entry(seq).or_default() += 1;
+        seq
+    }
+
+    /// Unregisters a snapshot taken by `pin`.
+    pub(super) fn unpin(&mut self, seq: u64) {
+        if let btree_map::Entry::Occupied(mut entry) = self.snapshots.entry(seq) {
+            *entry.get_mut() -= 1;
+            if *entry.get() == 0 {
+                entry.remove();
+            }
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! On-disk log files of the `KvStore`.
+//!
+//! Every command is stored as a checksummed frame, see `engines::frame`,
+//! whose payload is the command encoded with the `Codec` of the data directory.
+//!
+//! The commands of a write batch are nested in a single batch frame, flagged
+//! by the highest bit of the length. Its payload is the frame of every command,
+//! so the batch is checked as a whole on recovery, while each command can still
+//! be read and copied on its own.
+
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use super::codec::CommandCodec;
+use super::index::IndexWriter;
+use crate::engines::frame::{self, encode_frame, read_frame, Frame, BATCH_FLAG, HEADER_LEN};
+use crate::engines::now_millis;
+use crate::{KvsError, Result};
+
+/// Struct representing a command.
+#[derive(Serialize, Deserialize, Debug)]
+pub(super) enum Command {
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        // milliseconds since the unix epoch
+        #[serde(
+            default,
+            skip_serializing_if = "Option::is_none",
+            with = "super::codec::number::option"
+        )]
+        expires_at: Option<u64>,
+        // zero in the logs written before the sequence numbers
+        #[serde(default, with = "super::codec::number")]
+        seq: u64,
+        // milliseconds since the unix epoch, zero in the logs written before
+        #[serde(default, with = "super::codec::number")]
+        written_at: u64,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(default, with = "super::codec::number")]
+        seq: u64,
+        #[serde(default, with = "super::codec::number")]
+        written_at: u64,
+    },
+}
+
+impl Command {
+    /// Creates a set command written now.
+    pub(super) fn set(seq: u64, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Command {
+        Command::Set {
+            key,
+            value,
+            expires_at,
+            seq,
+            written_at: now_millis(),
+        }
+    }
+
+    /// Creates a remove command written now.
+    pub(super) fn remove(seq: u64, key: Vec<u8>) -> Command {
+        Command::Remove {
+            key,
+            seq,
+            written_at: now_millis(),
+        }
+    }
+
+    /// Returns the key written.
+    pub(super) fn key(&self) -> &[u8] {
+        match self {
+            Command::Set { key, .. } | Command::Remove { key, .. } => key,
+        }
+    }
+
+    /// Returns the sequence number of the write.
+    pub(super) fn seq(&self) -> u64 {
+        match self {
+            Command::Set { seq, .. } | Command::Remove { seq, .. } => *seq,
+        }
+    }
+
+    /// Returns the time of the write.
+    pub(super) fn written_at(&self) -> u64 {
+        match self {
+            Command::Set { written_at, .. } | Command::Remove { written_at, .. } => *written_at,
+        }
+    }
+
+    /// Returns the expiry time of a set command with a TTL.
+    pub(super) fn expires_at(&self) -> Option<u64> {
+        match self {
+            Command::Set { expires_at, .. } => *expires_at,
+            Command::Remove { .. } => None,
+        }
+    }
+}
+
+/// Represents the position and length of a command frame in the log.
+///
+/// The expiry time of the command is kept along, so expired entries are
+/// recognized without reading them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub(super) struct CommandPos {
+    pub(super) gen: u64,
+    pub(super) pos: u64,
+    pub(super) len: u64,
+    pub(super) expires_at: Option<u64>,
+}
+
+impl CommandPos {
+    /// Returns `true` if the entry has expired by `now`.
+    pub(super) fn is_expired(&self, now: u64) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+impl From<(u64, Range<u64>)> for CommandPos {
+    fn from((gen, range): (u64, Range<u64>)) -> Self {
+        CommandPos {
+            gen,
+            pos: range.start,
+            len: range.end - range.start,
+            expires_at: None,
+        }
+    }
+}
+
+/// Amount of live and stale bytes in a single log generation.
+#[derive(Default, Debug)]
+pub(super) struct GenStats {
+    pub(super) live: u64,
+    pub(super) stale: u64,
+}
+
+/// Accounts a freshly written entry as live data of its generation.
+pub(super) fn mark_live(stats: &mut BTreeMap<u64, GenStats>, cmd_pos: &CommandPos) {
+    stats.entry(cmd_pos.gen).or_default().live += cmd_pos.len;
+}
+
+/// Moves a previously live entry to the stale bytes of its generation.
+pub(super) fn mark_stale(stats: &mut BTreeMap<u64, GenStats>, cmd_pos: &CommandPos) {
+    let gen_stats = stats.entry(cmd_pos.gen).or_default();
+    gen_stats.live -= cmd_pos.len;
+    gen_stats.stale += cmd_pos.len;
+}
+
+/// A reader of the log files of a single `KvStore` directory.
+///
+/// File handles are opened lazily and closed once the compaction has deleted
+/// the generation they point to.
+pub(super) struct LogReader {
+    path: Arc<PathBuf>,
+    // generation of the latest compaction file; all older logs are deleted
+    safe_point: Arc<AtomicU64>,
+    codec: &'static dyn CommandCodec,
+    readers: HashMap<u64, BufReaderWithPos<File>>,
+}
+
+impl Clone for LogReader {
+    /// Creates a reader of the same directory with its own file handles.
+    fn clone(&self) -> LogReader {
+        LogReader::new(
+            Arc::clone(&self.path),
+            Arc::clone(&self.safe_point),
+            self.codec,
+        )
+    }
+}
+
+impl LogReader {
+    pub(super) fn new(
+        path: Arc<PathBuf>,
+        safe_point: Arc<AtomicU64>,
+        codec: &'static dyn CommandCodec,
+    ) -> LogReader {
+        LogReader {
+            path,
+            safe_point,
+            codec,
+            readers: HashMap::new(),
+        }
+    }
+
+    /// Returns the generation of the latest compaction file.
+    ///
+    /// It changes whenever a compaction deletes the older logs.
+    pub(super) fn safe_point(&self) -> u64 {
+        self.safe_point.load(Ordering::SeqCst)
+    }
+
+    /// Closes file handles of the generations deleted by the compaction.
+    pub(super) fn close_stale_handles(&mut self) {
+        let safe_point = self.safe_point.load(Ordering::SeqCst);
+        self.readers.retain(|&gen, _| gen >= safe_point);
+    }
+
+    /// Reads the log entry at the given position and passes it to `f`.
+    pub(super) fn read_and<F, R>(&mut self, cmd_pos: CommandPos, f: F) -> Result<R>
+    where
+        F: FnOnce(io::Take<&mut BufReaderWithPos<File>>) -> Result<R>,
+    {
+        self.close_stale_handles();
+
+        let reader = match self.readers.entry(cmd_pos.gen) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let file = File::open(log_path(&self.path, cmd_pos.gen))?;
+                entry.insert(BufReaderWithPos::new(file)?)
+            }
+        };
+        if reader.pos != cmd_pos.pos {
+            reader.seek(SeekFrom::Start(cmd_pos.pos))?;
+        }
+        f(reader.take(cmd_pos.len))
+    }
+
+    /// Reads, verifies and deserializes the command at the given position.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the frame is damaged.
+    pub(super) fn read_command(&mut self, cmd_pos: CommandPos) -> Result<Command> {
+        let corruption = || KvsError::Corruption {
+            generation: cmd_pos.gen,
+            offset: cmd_pos.pos,
+        };
+        let codec = self.codec;
+        self.read_and(cmd_pos, |mut cmd_reader| {
+            match read_frame(&mut cmd_reader)? {
+                Frame::Valid(payload) => codec.decode(&payload).map_err(|_| corruption()),
+                _ => Err(corruption()),
+            }
+        })
+    }
+}
+
+/// Encodes the command and writes it as a single frame.
+pub(super) fn write_command<W: Write>(
+    writer: &mut W,
+    codec: &dyn CommandCodec,
+    cmd: &Command,
+) -> Result<()> {
+    let payload = codec.encode(cmd)?;
+    writer.write_all(&encode_frame(&payload, 0))?;
+    Ok(())
+}
+
+/// Writes the commands as a single batch frame.
+///
+/// Returns the ranges of the nested command frames, relative to the start
+/// of the batch frame.
+pub(super) fn write_batch<W: Write>(
+    writer: &mut W,
+    codec: &dyn CommandCodec,
+    cmds: &[Command],
+) -> Result<Vec<Range<u64>>> {
+    let mut payload = Vec::new();
+    let mut ranges = Vec::with_capacity(cmds.len());
+    for cmd in cmds {
+        let start = (HEADER_LEN + payload.len()) as u64;
+        payload.extend_from_slice(&encode_frame(&codec.encode(cmd)?, 0));
+        ranges.push(start..(HEADER_LEN + payload.len()) as u64);
+    }
+    if payload.len() >= BATCH_FLAG as usize {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "write batch is too large").into());
+    }
+    writer.write_all(&encode_frame(&payload, BATCH_FLAG))?;
+    Ok(ranges)
+}
+
+/// Creates a new log file with given generation number and returns the writer to it.
+pub(super) fn new_log_file(path: &Path, gen: u64) -> Result<BufWriterWithPos<File>> {
+    let path = log_path(path, gen);
+    let writer = BufWriterWithPos::new(OpenOptions::new().create(true).append(true).open(path)?)?;
+    Ok(writer)
+}
+
+/// Returns sorted generation numbers in the given directory.
+pub(super) fn sorted_gen_list(path: &Path) -> Result<Vec<u64>> {
+    let mut gen_list: Vec<u64> = fs::read_dir(path)?
+        .flat_map(|res| -> Result<_> { Ok(res?.path()) })
+        .filter(|path| path.is_file() && path.extension() == Some("log".as_ref()))
+        .flat_map(|path| {
+            path.file_name()
+                .and_then(OsStr::to_str)
+                .map(|s| s.trim_end_matches(".log"))
+                .map(str::parse::<u64>)
+        })
+        .flatten()
+        .collect();
+    gen_list.sort_unstable();
+    Ok(gen_list)
+}
+
+/// Loads the whole log file and applies its commands to the index.
+///
+/// Live and stale bytes of the loaded entries are accounted in `stats`.
+/// A torn write at the end of the file is cut off, see `frame::replay`.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if a frame in the middle of the file is damaged.
+pub(super) fn load(
+    path: &Path,
+    gen: u64,
+    codec: &dyn CommandCodec,
+    index: &mut IndexWriter,
+    stats: &mut BTreeMap<u64, GenStats>,
+) -> Result<()> {
+    frame::replay(&log_path(path, gen), gen, |frame, range| {
+        let cmds = match frame {
+            Frame::Valid(payload) => codec
+                .decode(&payload)
+                .ok()
+                .map(|cmd| vec![(cmd, range.clone())]),
+            Frame::Batch(payload) => decode_batch(codec, &payload, range.start + HEADER_LEN as u64),
+            _ => None,
+        };
+        let cmds = match cmds {
+            Some(cmds) => cmds,
+            None => return false,
+        };
+        // the header of a batch frame is not part of any command.
+        let cmds_len: u64 = cmds.iter().map(|(_, range)| range.end - range.start).sum();
+        stats.entry(gen).or_default().stale += range.end - range.start - cmds_len;
+        for (cmd, range) in cmds {
+            let cmd_pos = CommandPos {
+                expires_at: cmd.expires_at(),
+                ..(gen, range).into()
+            };
+            index.apply_command(cmd, cmd_pos, stats);
+        }
+        true
+    })
+}
+
+/// Decodes the commands nested in the payload of a batch frame starting at
+/// `offset`, along with their positions.
+///
+/// Returns `None` if any of them is damaged.
+fn decode_batch(
+    codec: &dyn CommandCodec,
+    payload: &[u8],
+    offset: u64,
+) -> Option<Vec<(Command, Range<u64>)>> {
+    let mut cmds = Vec::new();
+    let mut rest = payload;
+    let mut pos = offset;
+    while !rest.is_empty() {
+        let remaining = rest.len();
+        let cmd = match read_frame(&mut rest).ok()? {
+            Frame::Valid(payload) => codec.decode(&payload).ok()?,
+            _ => return None,
+        };
+        let new_pos = pos + (remaining - rest.len()) as u64;
+        cmds.push((cmd, pos..new_pos));
+        pos = new_pos;
+    }
+    Some(cmds)
+}
+
+pub(super) fn log_path(dir: &Path, gen: u64) -> PathBuf {
+    dir.join(format!("{}.log", gen))
+}
+
+pub(super) struct BufReaderWithPos<R: Read + Seek> {
+    reader: BufReader<R>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> BufReaderWithPos<R> {
+    pub(super) fn new(mut inner: R) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufReaderWithPos {
+            reader: BufReader::new(inner),
+            pos,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for BufReaderWithPos<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.reader.read(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+}
+
+impl<R: Read + Seek> Seek for BufReaderWithPos<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.reader.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+pub(super) struct BufWriterWithPos<W: Write + Seek> {
+    writer: BufWriter<W>,
+    pub(super) pos: u64,
+}
+
+impl<W: Write + Seek> BufWriterWithPos<W> {
+    pub(super) fn new(mut inner: W) -> Result<Self> {
+        let pos = inner.stream_position()?;
+        Ok(BufWriterWithPos {
+            writer: BufWriter::new(inner),
+            pos,
+        })
+    }
+}
+
+impl BufWriterWithPos<File> {
+    /// Returns the underlying file.
+    pub(super) fn get_ref(&self) -> &File {
+        self.writer.get_ref()
+    }
+
+    /// Flushes the buffer and waits until the file content reaches the disk.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+impl<W: Write + Seek> Write for BufWriterWithPos<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.writer.write(buf)?;
+        self.pos += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for BufWriterWithPos<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.pos = self.writer.seek(pos)?;
+        Ok(self.pos)
+    }
+}
@@ -0,0 +1,679 @@
+//! `KvStore`, the log-structured engine: append-only logs and an in-memory index.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, UNIX_EPOCH};
+
+pub use self::codec::Codec;
+use self::codec::{read_header, write_header};
+use self::compaction::{Compactor, Shared};
+use self::hint::load_hint;
+use self::index::Index;
+pub use self::index::KeyVersion;
+use self::log::{load, sorted_gen_list, Command, GenStats, LogReader};
+pub use self::options::{KvStoreOptions, Sync};
+pub use self::snapshot::Snapshot;
+pub use self::transaction::Transaction;
+use self::writer::{GroupCommit, LogWriter};
+use super::{
+    check_removes, expiry_time, now_millis, time_left, BatchOp, DirLock, KvEngine, Scan,
+    WatchEvent, Watcher, Watchers, WriteBatch,
+};
+use crate::{KvsError, Result};
+
+mod codec;
+mod compaction;
+mod hint;
+mod index;
+mod log;
+mod options;
+mod snapshot;
+mod syncer;
+mod transaction;
+mod writer;
+
+/// The `KvStore` stores key/value pairs of byte strings.
+///
+/// Key/value pairs are persisted to disk in log files. Log files are named after
+/// monotonically increasing generation numbers with a `log` extension name.
+/// A lock-free skip list in memory stores the keys and the value locations for
+/// fast query and ordered scans, so reads never wait for writes.
+///
+/// Overwritten and removed entries become stale. Once the amount of stale bytes
+/// exceeds the compaction threshold, a background thread rewrites the live entries
+/// into a fresh generation and deletes the old log files. Reads and writes are
+/// served meanwhile: new entries go to a newer generation, and the index is
+/// switched over to the compacted entries atomically. A read racing with the
+/// deletion of the old log files looks the key up again and reads the compacted
+/// entry instead.
+///
+/// Keys set with a TTL carry their expiry time in the log record. Expired keys
+/// are hidden from reads, swept out of the index in the background and not
+/// copied by compactions.
+///
+/// Every write creates a new version of its key. Older versions are kept as
+/// long as the retention policy of the `KvStoreOptions` asks for them, so they
+/// can be listed with `KvStore::history` and read with `KvStore::get_at`.
+///
+/// A `Snapshot` taken with `KvStore::snapshot` keeps reading the store as it
+/// was at that moment. Every write is numbered, and the index keeps the older
+/// versions of the keys, along with their log records, as long as a snapshot
+/// may still read them.
+///
+/// A `Transaction` started with `KvStore::transaction` reads from a snapshot,
+/// buffers its writes and commits them as a batch, unless a key it read has
+/// been written since the snapshot was taken.
+///
+/// Every compacted log is accompanied by a hint file holding the locations of
+/// its entries, so opening a store only has to replay the logs written since
+/// the last compaction.
+///
+/// The commands are encoded with the `Codec` recorded in the header of the
+/// data directory.
+///
+/// How often the writes are forced to the disk is chosen with the `Sync` mode
+/// of the `KvStoreOptions` the store is opened with. In the `Sync::Always` mode
+/// concurrent writes through cloned handles share a single sync.
+///
+/// ```rust
+/// # use kvs::{KvEngine, KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "value".to_owned())?;
+/// let val = store.get("key".to_owned())?;
+/// assert_eq!(val, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct KvStore {
+    // directory for the log and other data
+    path: Arc<PathBuf>,
+    index: Arc<Index>,
+    // live and stale bytes of every generation
+    stats: Arc<Mutex<BTreeMap<u64, GenStats>>>,
+    // every handle reads the logs through its own files
+    reader: RefCell<LogReader>,
+    writer: Arc<Mutex<LogWriter>>,
+    group_commit: Arc<GroupCommit>,
+    options: Arc<KvStoreOptions>,
+    compactor: Arc<Compactor>,
+    watchers: Arc<Watchers>,
+    // released once the last handle is dropped, after the compaction thread
+    // has stopped
+    _lock: Arc<DirLock>,
+}
+
+impl KvStore {
+    /// Opens a `KvStore` with the given path and the default options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if the directory is already
+    /// opened, by this or another process.
+    ///
+    /// It propagates I/O errors during the log replay.
+    ///
+    /// It returns `KvsError::Corruption` if a log record is damaged. A record
+    /// cut off at the end of a log is discarded instead.
+    pub fn open(path: impl Into<PathBuf>) -> Result<KvStore> {
+        KvStore::open_with(path, &KvStoreOptions::new())
+    }
+
+    /// Opens a `KvStore` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Same as `KvStore::open`.
+    pub fn open_with(path: impl Into<PathBuf>, options: &KvStoreOptions) -> Result<KvStore> {
+        let path = Arc::new(path.into());
+        fs::create_dir_all(&*path)?;
+        let lock = DirLock::acquire(&path)?;
+
+        let index = Index::new(options.retention);
+        let mut stats = BTreeMap::new();
+
+        let gen_list = sorted_gen_list(&path)?;
+
+        let codec = match read_header(&path)? {
+            Some(codec) => codec,
+            None => {
+                // logs without a header were written before the codec was configurable.
+                let codec = if gen_list.is_empty() {
+                    options.codec
+                } else {
+                    Codec::Json
+                };
+                write_header(&path, codec)?;
+                codec
+            }
+        }
+        .command_codec();
+
+        {
+            let mut index = index.write();
+            for &gen in &gen_list {
+                // compacted logs come with a hint file, so only the others are replayed.
+                if !load_hint(&path, gen, &mut index, &mut stats)? {
+                    load(&path, gen, codec, &mut index, &mut stats)?;
+                }
+            }
+        }
+
+        let current_gen = gen_list.last().unwrap_or(&0) + 1;
+        let writer = LogWriter::new(
+            Arc::clone(&path),
+            current_gen,
+            options.sync,
+            codec,
+            index.last_seq(),
+        )?;
+
+        let index = Arc::new(index);
+        let stats = Arc::new(Mutex::new(stats));
+        let safe_point = Arc::new(AtomicU64::new(0));
+        let reader = LogReader::new(Arc::clone(&path), Arc::clone(&safe_point), codec);
+        let compactor = Compactor::spawn(
+            Shared {
+                path: Arc::clone(&path),
+                index: Arc::clone(&index),
+                stats: Arc::clone(&stats),
+                safe_point,
+            },
+            reader.clone(),
+            options.ttl_sweep_interval,
+        )?;
+
+        Ok(KvStore {
+            path,
+            index,
+            stats,
+            reader: RefCell::new(reader),
+            writer: Arc::new(Mutex::new(writer)),
+            group_commit: Arc::new(GroupCommit::default()),
+            options: Arc::new(options.clone()),
+            compactor: Arc::new(compactor),
+            watchers: Arc::new(Watchers::default()),
+            _lock: Arc::new(lock),
+        })
+    }
+
+    /// Returns the version of the latest write.
+    ///
+    /// Every write gets a new version, greater than the ones before it.
+    /// The writes of a batch share a version.
+    pub fn version(&self) -> u64 {
+        self.index.last_seq()
+    }
+
+    /// Gets the value of a key as of the given version.
+    ///
+    /// Returns `None` if the key did not exist then, or if the versions of
+    /// the key written up to then are no longer retained.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the log record of the value is damaged.
+    pub fn get_at(&self, key: Vec<u8>, version: u64) -> Result<Option<Vec<u8>>> {
+        self.read_at(&key, version)
+    }
+
+    /// Lists the retained versions of a key, oldest first.
+    ///
+    /// Besides the current version, the older ones are retained as configured
+    /// by `KvStoreOptions::retain_versions` and `KvStoreOptions::retain_for`,
+    /// or as long as a snapshot reads them.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the log record of a value is damaged.
+    pub fn history(&self, key: Vec<u8>) -> Result<Vec<KeyVersion>> {
+        let index = &self.index;
+        self.read_retrying(|reader| {
+            let versions = match index.get(&key) {
+                Some(versions) => versions,
+                None => return Ok(Vec::new()),
+            };
+            versions
+                .iter()
+                .map(|version| {
+                    let value = if version.removed {
+                        None
+                    } else if let Command::Set { value, .. } = reader.read_command(version.pos)? {
+                        Some(value)
+                    } else {
+                        return Err(KvsError::UnexpectedCommandType);
+                    };
+                    Ok(KeyVersion {
+                        version: version.seq,
+                        written_at: UNIX_EPOCH + Duration::from_millis(version.written_at),
+                        value,
+                    })
+                })
+                .collect()
+        })
+    }
+
+    /// Takes a read-only snapshot of the store.
+    ///
+    /// The snapshot sees all the writes made so far and none of the later
+    /// ones, until it is dropped.
+    pub fn snapshot(&self) -> Snapshot {
+        let seq = self.index.write().pin();
+        Snapshot::new(self.clone(), seq)
+    }
+
+    /// Starts an optimistic transaction reading from a snapshot taken now.
+    pub fn transaction(&self) -> Transaction {
+        Transaction::new(self.snapshot())
+    }
+
+    /// Compacts all the log generations written so far and waits for the
+    /// compaction to finish.
+    ///
+    /// Compaction normally runs in the background once the stale bytes exceed
+    /// the threshold, so this is mostly useful in tests.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O errors of the compaction.
+    pub fn compact_now(&self) -> Result<()> {
+        let compaction_gen = self.writer.lock().unwrap().roll()?;
+        self.compactor.schedule(compaction_gen)?.wait()
+    }
+
+    /// Schedules a compaction if the stale bytes exceed the threshold and
+    /// no compaction is running yet.
+    fn maybe_compact(&self, writer: &mut LogWriter) -> Result<()> {
+        if self.compactor.in_progress() {
+            return Ok(());
+        }
+        let stale_bytes: u64 = self
+            .stats
+            .lock()
+            .unwrap()
+            .values()
+            .map(|stats| stats.stale)
+            .sum();
+        if stale_bytes > self.options.compaction_threshold {
+            let compaction_gen = writer.roll()?;
+            self.compactor.schedule(compaction_gen)?;
+        }
+        Ok(())
+    }
+
+    /// Appends the command to the log and applies it to the index.
+    ///
+    /// Returns the ticket to `commit` the write with.
+    fn append(&self, writer: &mut LogWriter, cmd: Command) -> Result<u64> {
+        let cmd_pos = writer.append(&cmd)?;
+        let events = self.watch_events(std::slice::from_ref(&cmd));
+        self.index
+            .write()
+            .apply_command(cmd, cmd_pos, &mut self.stats.lock().unwrap());
+        self.watchers.notify(events);
+        self.maybe_compact(writer)?;
+        Ok(writer.appended())
+    }
+
+    /// Appends the writes of the batch to the log as a single frame and applies
+    /// them to the index.
+    ///
+    /// Returns the ticket to `commit` the batch with.
+    fn append_batch(&self, writer: &mut LogWriter, batch: WriteBatch) -> Result<u64> {
+        // all the writes of the batch share a sequence number, so
+        // a snapshot sees either all or none of them.
+        let seq = writer.next_seq();
+        let cmds: Vec<Command> = batch
+            .into_iter()
+            .map(|op| match op {
+                BatchOp::Set { key, value } => Command::set(seq, key, value, None),
+                BatchOp::Remove { key } => Command::remove(seq, key),
+            })
+            .collect();
+        let appended = writer.appended();
+        let positions = writer.append_batch(&cmds)?;
+        let events = self.watch_events(&cmds);
+        {
+            let mut index = self.index.write();
+            let mut stats = self.stats.lock().unwrap();
+            // the header of the batch frame is not part of any command.
+            let cmds_len: u64 = positions.iter().map(|cmd_pos| cmd_pos.len).sum();
+            stats.entry(positions[0].gen).or_default().stale +=
+                writer.appended() - appended - cmds_len;
+            for (cmd, cmd_pos) in cmds.into_iter().zip(positions) {
+                index.apply_command(cmd, cmd_pos, &mut stats);
+            }
+        }
+        self.watchers.notify(events);
+        self.maybe_compact(writer)?;
+        Ok(writer.appended())
+    }
+
+    /// Returns the events of the commands the watchers are interested in.
+    ///
+    /// They are sent once the commands are applied, so a watcher reading
+    /// the key sees the write.
+    fn watch_events(&self, cmds: &[Command]) -> Vec<WatchEvent> {
+        self.watchers.events(cmds.iter().map(|cmd| match cmd {
+            Command::Set { value, .. } => (cmd.key(), Some(value.as_slice())),
+            Command::Remove { .. } => (cmd.key(), None),
+        }))
+    }
+
+    /// Applies the writes of a transaction reading from the snapshot `seq`,
+    /// unless any of the keys it read has been written since.
+    fn commit_transaction(
+        &self,
+        seq: u64,
+        reads: &BTreeSet<Vec<u8>>,
+        batch: WriteBatch,
+    ) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            // the snapshot of the transaction keeps the versions of the
+            // keys it read, so a removal is noticed as well.
+            if reads.iter().any(|key| {
+                self.index
+                    .latest_seq(key)
+                    .is_some_and(|latest| latest > seq)
+            }) {
+                return Err(KvsError::TxnConflict);
+            }
+            if batch.is_empty() {
+                return Ok(());
+            }
+            self.append_batch(&mut writer, batch)?
+        };
+        self.commit(ticket)
+    }
+
+    /// Gets the value of a key as seen by the writes up to `seq`.
+    fn read_at(&self, key: &[u8], seq: u64) -> Result<Option<Vec<u8>>> {
+        let index = &self.index;
+        self.read_retrying(|reader| match index.lookup(key, seq, now_millis()) {
+            Some(version) => {
+                if let Command::Set { value, .. } = reader.read_command(version.pos)? {
+                    Ok(Some(value))
+                } else {
+                    Err(KvsError::UnexpectedCommandType)
+                }
+            }
+            None => Ok(None),
+        })
+    }
+
+    /// Runs a read of the index and the logs, starting it over if a compaction
+    /// deleted the logs it was reading meanwhile.
+    ///
+    /// No lock is held during the read. The compaction switches the index over
+    /// to the compacted entries before it deletes the old logs, so the index
+    /// looked up again points to files which still exist.
+    fn read_retrying<T>(&self, mut read: impl FnMut(&mut LogReader) -> Result<T>) -> Result<T> {
+        let mut reader = self.reader.borrow_mut();
+        loop {
+            let safe_point = reader.safe_point();
+            match read(&mut reader) {
+                Err(_) if reader.safe_point() != safe_point => continue,
+                result => return result,
+            }
+        }
+    }
+
+    /// Iterates over the keys within the range as seen by the writes up to `seq`.
+    ///
+    /// The keys are taken from the index up front, while every value is read
+    /// from the log only when the iterator reaches it.
+    fn scan_at<R: RangeBounds<Vec<u8>>>(&self, range: R, seq: u64) -> Scan<'_> {
+        let keys: Vec<Vec<u8>> = self
+            .index
+            .range(range)
+            .filter(|entry| entry.value().with(|versions| versions.at(seq).is_some()))
+            .map(|entry| entry.key().clone())
+            .collect();
+        Box::new(
+            keys.into_iter()
+                .filter_map(move |key| match self.read_at(&key, seq) {
+                    Ok(Some(value)) => Some(Ok((key, value))),
+                    Ok(None) => None,
+                    Err(e) => Some(Err(e)),
+                }),
+        )
+    }
+
+    /// Returns `true` if the key currently holds a value.
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.index.lookup(key, u64::MAX, now_millis()).is_some()
+    }
+
+    /// Waits for the write which ended at `ticket` to become durable
+    /// according to the `Sync` mode.
+    fn commit(&self, ticket: u64) -> Result<()> {
+        match self.options.sync {
+            Sync::Always => self.group_commit.wait(ticket, &self.writer),
+            Sync::Interval(_) | Sync::Never => Ok(()),
+        }
+    }
+}
+
+impl Clone for KvStore {
+    /// Creates another handle to the same store.
+    ///
+    /// The handles share the index and the writer, but read the logs through
+    /// their own file handles.
+    fn clone(&self) -> KvStore {
+        KvStore {
+            path: Arc::clone(&self.path),
+            index: Arc::clone(&self.index),
+            stats: Arc::clone(&self.stats),
+            reader: RefCell::new(self.reader.borrow().clone()),
+            writer: Arc::clone(&self.writer),
+            group_commit: Arc::clone(&self.group_commit),
+            options: Arc::clone(&self.options),
+            compactor: Arc::clone(&self.compactor),
+            watchers: Arc::clone(&self.watchers),
+            _lock: Arc::clone(&self._lock),
+        }
+    }
+}
+
+impl KvEngine for KvStore {
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            let cmd = Command::set(writer.next_seq(), key, value, None);
+            self.append(&mut writer, cmd)?
+        };
+        self.commit(ticket)
+    }
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// The expiry time is stored in the log record. Expired keys are hidden
+    /// right away, removed from the index by a background sweep and dropped
+    /// by the next compaction.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            let cmd = Command::set(writer.next_seq(), key, value, Some(expiry_time(ttl)));
+            self.append(&mut writer, cmd)?
+        };
+        self.commit(ticket)
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnexpectedCommandType` if the given command type unexpected.
+    ///
+    /// It returns `KvsError::Corruption` if the log record of the value is damaged.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.read_at(&key, u64::MAX)
+    }
+
+    /// Gets the value of a given key along with the time left until it expires.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the log record of the value is damaged.
+    fn get_bytes_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        let index = &self.index;
+        self.read_retrying(|reader| {
+            let now = now_millis();
+            match index.lookup(&key, u64::MAX, now) {
+                Some(version) => match reader.read_command(version.pos)? {
+                    Command::Set {
+                        value, expires_at, ..
+                    } => Ok(Some((value, expires_at.map(|at| time_left(at, now))))),
+                    Command::Remove { .. } => Err(KvsError::UnexpectedCommandType),
+                },
+                None => Ok(None),
+            }
+        })
+    }
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// The keys are taken from the index up front, while every value is read
+    /// from the log only when the iterator reaches it.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>> {
+        Ok(self.scan_at(range, u64::MAX))
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            if !self.contains_key(&key) {
+                return Err(KvsError::KeyNotFound);
+            }
+            let cmd = Command::remove(writer.next_seq(), key);
+            self.append(&mut writer, cmd)?
+        };
+        self.commit(ticket)
+    }
+
+    /// Sets or removes a key if its current value is the expected one.
+    ///
+    /// The check and the write happen under the writer lock, so no other
+    /// write to the store can come in between.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or serialization errors during reading or writing the log.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            let current = self.get_bytes(key.clone())?;
+            if current != expected {
+                return Ok(false);
+            }
+            let cmd = match (current, new) {
+                (_, Some(value)) => Command::set(writer.next_seq(), key, value, None),
+                (Some(_), None) => Command::remove(writer.next_seq(), key),
+                (None, None) => return Ok(true),
+            };
+            self.append(&mut writer, cmd)?
+        };
+        self.commit(ticket)?;
+        Ok(true)
+    }
+
+    /// Applies all the writes of the batch atomically.
+    ///
+    /// The batch is persisted as a single log frame, so a torn write drops
+    /// the whole batch on recovery.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let ticket = {
+            let mut writer = self.writer.lock().unwrap();
+            check_removes(&batch, |key| Ok(self.contains_key(key)))?;
+            self.append_batch(&mut writer, batch)?
+        };
+        self.commit(ticket)
+    }
+
+    /// Applies the batch if the read keys still hold the values they were
+    /// read with.
+    ///
+    /// The values are checked and the batch is replayed in a `Transaction`,
+    /// so a concurrent write in between is detected as well.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn transact(&self, reads: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch) -> Result<()> {
+        let mut txn = self.transaction();
+        for (key, value) in reads {
+            if txn.get_bytes(key)? != value {
+                return Err(KvsError::TxnConflict);
+            }
+        }
+        for op in batch {
+            match op {
+                BatchOp::Set { key, value } => txn.set_bytes(key, value),
+                BatchOp::Remove { key } => txn.remove_bytes(key)?,
+            }
+        }
+        txn.commit()
+    }
+
+    /// Watches the writes to the keys starting with the prefix.
+    ///
+    /// The events are sent from the write path right after the writes are
+    /// applied, under the writer lock, so they come in the order of the log.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        Ok(self.watchers.subscribe(prefix))
+    }
+
+    /// Syncs the current log to the disk, regardless of the `Sync` mode.
+    fn flush(&self) -> Result<()> {
+        self.writer.lock().unwrap().sync()
+    }
+}
@@ -0,0 +1,135 @@
+//! Options a `KvStore` is opened with.
+
+use std::time::Duration;
+
+use super::codec::Codec;
+
+/// Default amount of stale bytes that triggers a compaction.
+const DEFAULT_COMPACTION_THRESHOLD: u64 = 1024 * 1024;
+
+/// Default interval of removing the expired keys from the index.
+const DEFAULT_TTL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Durability mode of the `KvStore` writes.
+///
+/// Every write reaches the operating system before it returns, so it survives
+/// a crash of the process. The mode controls when the data is forced to the
+/// disk, which is what it takes to survive a crash of the machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sync {
+    /// Every write is synced to the disk before it returns.
+    Always,
+    /// The log is synced to the disk in the background with the given interval.
+    Interval(Duration),
+    /// Syncing is left to the operating system, unless `KvEngine::flush` is called.
+    Never,
+}
+
+/// Which of the older versions of a key are kept besides the ones
+/// the live snapshots read.
+#[derive(Clone, Copy, Debug)]
+pub(super) struct Retention {
+    /// Number of the latest versions kept, including the current one.
+    pub(super) versions: usize,
+    /// How long a version is kept after it has been overwritten.
+    pub(super) duration: Duration,
+}
+
+/// Options to open a `KvStore` with.
+///
+/// ```rust
+/// # use kvs::{KvStore, KvStoreOptions, Result, Sync};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// use std::time::Duration;
+/// let mut options = KvStoreOptions::new();
+/// options
+///     .sync(Sync::Interval(Duration::from_millis(100)))
+///     .compaction_threshold(64 * 1024 * 1024);
+/// let store = KvStore::open_with(current_dir()?, &options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct KvStoreOptions {
+    pub(super) sync: Sync,
+    pub(super) compaction_threshold: u64,
+    pub(super) codec: Codec,
+    pub(super) ttl_sweep_interval: Duration,
+    pub(super) retention: Retention,
+}
+
+impl KvStoreOptions {
+    /// Creates the default options: writes are never synced explicitly,
+    /// a compaction is triggered by 1MB of stale data, new data directories
+    /// use the json codec, expired keys are swept every second and only
+    /// the current version of every key is kept.
+    pub fn new() -> Self {
+        KvStoreOptions {
+            sync: Sync::Never,
+            compaction_threshold: DEFAULT_COMPACTION_THRESHOLD,
+            codec: Codec::Json,
+            ttl_sweep_interval: DEFAULT_TTL_SWEEP_INTERVAL,
+            retention: Retention {
+                versions: 1,
+                duration: Duration::ZERO,
+            },
+        }
+    }
+
+    /// Sets the durability mode of the writes.
+    pub fn sync(&mut self, sync: Sync) -> &mut Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Sets the amount of stale bytes which triggers a compaction.
+    pub fn compaction_threshold(&mut self, threshold: u64) -> &mut Self {
+        self.compaction_threshold = threshold;
+        self
+    }
+
+    /// Sets the codec of the log records.
+    ///
+    /// It only applies to new data directories: an existing directory is
+    /// always opened with the codec recorded in its header.
+    pub fn codec(&mut self, codec: Codec) -> &mut Self {
+        self.codec = codec;
+        self
+    }
+
+    /// Sets how often the expired keys are removed from the index.
+    ///
+    /// Expired keys are never returned by reads in any case, the sweep only
+    /// frees their memory and accounts their bytes as stale.
+    pub fn ttl_sweep_interval(&mut self, interval: Duration) -> &mut Self {
+        self.ttl_sweep_interval = interval;
+        self
+    }
+
+    /// Keeps the given number of the latest versions of every key, including
+    /// the current one, for `KvStore::history` and `KvStore::get_at`.
+    ///
+    /// A removal counts as a version too. The current version is always kept.
+    pub fn retain_versions(&mut self, versions: usize) -> &mut Self {
+        self.retention.versions = versions;
+        self
+    }
+
+    /// Keeps the versions of every key for the given duration after they
+    /// have been overwritten or removed.
+    ///
+    /// Versions are kept if either `retain_versions` or `retain_for` asks
+    /// for them. Once neither does, they leave the index with the periodic
+    /// sweep of the expired keys and the disk with the next compaction.
+    pub fn retain_for(&mut self, duration: Duration) -> &mut Self {
+        self.retention.duration = duration;
+        self
+    }
+}
+
+impl Default for KvStoreOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,89 @@
+//! Read-only snapshots of a `KvStore` at a sequence number.
+
+use std::ops::RangeBounds;
+
+use super::KvStore;
+use crate::engines::{prefix_range, Scan};
+use crate::Result;
+
+/// A read-only view of a `KvStore` as it was when the snapshot was taken.
+///
+/// Writes made after that are not visible through the snapshot, while the
+/// versions it reads are kept in the index and survive compactions until
+/// the snapshot is dropped. Keys set with a TTL still expire on time.
+///
+/// ```rust
+/// # use kvs::{KvEngine, KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("key".to_owned(), "old".to_owned())?;
+/// let snapshot = store.snapshot();
+/// store.set("key".to_owned(), "new".to_owned())?;
+/// assert_eq!(snapshot.get("key".to_owned())?, Some("old".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct Snapshot {
+    // a handle of its own, so the snapshot reads through its own files
+    pub(super) store: KvStore,
+    seq: u64,
+}
+
+impl Snapshot {
+    pub(super) fn new(store: KvStore, seq: u64) -> Snapshot {
+        Snapshot { store, seq }
+    }
+
+    /// Returns the sequence number of the latest write the snapshot sees.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Corruption` if the log record of the value is damaged.
+    pub fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.store.read_at(&key, self.seq)
+    }
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of the range is greater than its end.
+    pub fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>> {
+        Ok(self.store.scan_at(range, self.seq))
+    }
+
+    /// Iterates over the keys starting with the prefix, in sorted order.
+    pub fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Scan<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+}
+
+impl Drop for Snapshot {
+    /// Releases the versions only the snapshot reads, so the next
+    /// compaction can drop them.
+    fn drop(&mut self) {
+        self.store.index.write().unpin(self.seq);
+    }
+}
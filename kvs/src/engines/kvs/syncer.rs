@@ -0,0 +1,57 @@
+//! Periodic syncing of the active `KvStore` log.
+
+use std::fs::File;
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::Result;
+
+/// Handle to a thread syncing the active log to the disk with a fixed interval.
+///
+/// The log is synced one last time when the handle is dropped.
+pub(super) struct Syncer {
+    file: Arc<Mutex<File>>,
+    // dropped to stop the thread
+    stop: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Syncer {
+    /// Spawns the syncing thread for the given log file.
+    pub(super) fn spawn(file: File, interval: Duration) -> Result<Syncer> {
+        let file = Arc::new(Mutex::new(file));
+        let thread_file = Arc::clone(&file);
+        let (stop, stopped) = mpsc::channel::<()>();
+        let handle = thread::Builder::new()
+            .name("kvs-syncer".to_owned())
+            .spawn(move || {
+                while let Err(RecvTimeoutError::Timeout) = stopped.recv_timeout(interval) {
+                    // a failed sync is retried on the next tick.
+                    let _ = thread_file.lock().unwrap().sync_data();
+                }
+                let _ = thread_file.lock().unwrap().sync_data();
+            })?;
+
+        Ok(Syncer {
+            file,
+            stop: Some(stop),
+            handle: Some(handle),
+        })
+    }
+
+    /// Switches the syncing to a new active log.
+    pub(super) fn set_file(&self, file: File) {
+        *self.file.lock().unwrap() = file;
+    }
+}
+
+impl Drop for Syncer {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
@@ -0,0 +1,144 @@
+//! Optimistic transactions over a `KvStore` snapshot.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use super::Snapshot;
+use crate::{KvsError, Result, WriteBatch};
+
+/// An optimistic transaction over a `KvStore`.
+///
+/// Reads come from a snapshot taken when the transaction started, and see
+/// the writes of the transaction itself. The writes are buffered until
+/// `commit`, which applies them atomically unless a key the transaction read
+/// has been written since the snapshot, in which case it fails with
+/// `KvsError::TxnConflict` and nothing is written.
+///
+/// Dropping a transaction without committing it discards its writes.
+///
+/// ```rust
+/// # use kvs::{KvEngine, KvStore, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let store = KvStore::open(current_dir()?)?;
+/// store.set("from".to_owned(), "10".to_owned())?;
+/// let mut txn = store.transaction();
+/// let from: u32 = txn.get("from".to_owned())?.unwrap().parse().unwrap();
+/// txn.set("from".to_owned(), (from - 1).to_string());
+/// txn.set("to".to_owned(), "1".to_owned());
+/// txn.commit()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Transaction {
+    snapshot: Snapshot,
+    // keys read from the snapshot, checked for later writes on commit
+    reads: BTreeSet<Vec<u8>>,
+    // buffered writes, `None` removing the key
+    writes: BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl Transaction {
+    pub(super) fn new(snapshot: Snapshot) -> Transaction {
+        Transaction {
+            snapshot,
+            reads: BTreeSet::new(),
+            writes: BTreeMap::new(),
+        }
+    }
+
+    /// Returns the sequence number of the snapshot the transaction reads from.
+    pub fn seq(&self) -> u64 {
+        self.snapshot.seq()
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    pub fn get(&mut self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Gets the value of a given key, as written by the transaction or
+    /// else as seen by its snapshot.
+    pub fn get_bytes(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        match self.writes.get(&key) {
+            Some(value) => Ok(value.clone()),
+            None => self.read(key),
+        }
+    }
+
+    /// Sets the value of a string key to a string on commit.
+    pub fn set(&mut self, key: String, value: String) {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a key on commit.
+    pub fn set_bytes(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.writes.insert(key, Some(value));
+    }
+
+    /// Removes a given string key on commit.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove(&mut self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Removes a given key on commit.
+    ///
+    /// The key is read to check that it exists, so a concurrent write to it
+    /// makes the commit fail. A key only set by the transaction itself is
+    /// just dropped from its writes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    pub fn remove_bytes(&mut self, key: Vec<u8>) -> Result<()> {
+        if self.get_bytes(key.clone())?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        if self.read(key.clone())?.is_some() {
+            self.writes.insert(key, None);
+        } else {
+            self.writes.remove(&key);
+        }
+        Ok(())
+    }
+
+    /// Reads a key from the snapshot, regardless of the writes of the
+    /// transaction, and records the read.
+    fn read(&mut self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        let value = self.snapshot.get_bytes(key.clone())?;
+        self.reads.insert(key);
+        Ok(value)
+    }
+
+    /// Applies the writes of the transaction atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if a key read by the transaction
+    /// has been written since its snapshot was taken.
+    ///
+    /// It propagates I/O or serialization errors during writing the log.
+    pub fn commit(self) -> Result<()> {
+        let mut batch = WriteBatch::new();
+        for (key, value) in self.writes {
+            match value {
+                Some(value) => batch.set(key, value),
+                None => batch.remove(key),
+            };
+        }
+        let seq = self.snapshot.seq();
+        self.snapshot
+            .store
+            .commit_transaction(seq, &self.reads, batch)
+    }
+}
@@ -0,0 +1,185 @@
+//! Appending to the `KvStore` log and the group commit of the writes.
+
+use std::fs::File;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::{Arc, Condvar, Mutex};
+
+use super::codec::CommandCodec;
+use super::log::{new_log_file, write_batch, write_command, BufWriterWithPos, Command, CommandPos};
+use super::options::Sync;
+use super::syncer::Syncer;
+use crate::Result;
+
+/// The single writer of the current log, shared by all the `KvStore` handles.
+pub(super) struct LogWriter {
+    path: Arc<PathBuf>,
+    writer: BufWriterWithPos<File>,
+    current_gen: u64,
+    sync: Sync,
+    codec: &'static dyn CommandCodec,
+    // another handle to the current log, synced without holding the writer lock
+    sync_file: Arc<File>,
+    // syncs the current log in the `Sync::Interval` mode
+    syncer: Option<Syncer>,
+    // total amount of bytes appended since open, used as group commit tickets
+    appended: u64,
+    // sequence number of the latest write
+    last_seq: u64,
+}
+
+impl LogWriter {
+    /// Creates the log of the given generation and a writer to it.
+    ///
+    /// The writes are numbered from `last_seq + 1` on.
+    pub(super) fn new(
+        path: Arc<PathBuf>,
+        gen: u64,
+        sync: Sync,
+        codec: &'static dyn CommandCodec,
+        last_seq: u64,
+    ) -> Result<LogWriter> {
+        let writer = new_log_file(&path, gen)?;
+        let sync_file = Arc::new(writer.get_ref().try_clone()?);
+        let syncer = match sync {
+            Sync::Interval(interval) => {
+                Some(Syncer::spawn(writer.get_ref().try_clone()?, interval)?)
+            }
+            Sync::Always | Sync::Never => None,
+        };
+        Ok(LogWriter {
+            path,
+            writer,
+            current_gen: gen,
+            sync,
+            codec,
+            sync_file,
+            syncer,
+            appended: 0,
+            last_seq,
+        })
+    }
+
+    /// Returns the sequence number of the next write.
+    pub(super) fn next_seq(&mut self) -> u64 {
+        self.last_seq += 1;
+        self.last_seq
+    }
+
+    /// Returns the total amount of bytes appended so far.
+    pub(super) fn appended(&self) -> u64 {
+        self.appended
+    }
+
+    /// Appends the command to the current log and hands it over to the OS.
+    ///
+    /// In the `Sync::Always` mode the write is not durable until the group
+    /// commit has synced the log past `appended()`.
+    ///
+    /// Returns the position of the written command.
+    pub(super) fn append(&mut self, cmd: &Command) -> Result<CommandPos> {
+        let pos = self.writer.pos;
+        write_command(&mut self.writer, self.codec, cmd)?;
+        self.writer.flush()?;
+        self.appended += self.writer.pos - pos;
+        Ok(CommandPos {
+            expires_at: cmd.expires_at(),
+            ..(self.current_gen, pos..self.writer.pos).into()
+        })
+    }
+
+    /// Appends the commands to the current log as a single batch frame.
+    ///
+    /// Returns the positions of the written commands.
+    pub(super) fn append_batch(&mut self, cmds: &[Command]) -> Result<Vec<CommandPos>> {
+        let pos = self.writer.pos;
+        let ranges = write_batch(&mut self.writer, self.codec, cmds)?;
+        self.writer.flush()?;
+        self.appended += self.writer.pos - pos;
+        Ok(cmds
+            .iter()
+            .zip(ranges)
+            .map(|(cmd, range)| CommandPos {
+                expires_at: cmd.expires_at(),
+                ..(self.current_gen, pos + range.start..pos + range.end).into()
+            })
+            .collect())
+    }
+
+    /// Seals the current log and starts a new one.
+    ///
+    /// The generation between the sealed and the new log is left for the
+    /// compaction and returned.
+    pub(super) fn roll(&mut self) -> Result<u64> {
+        match self.sync {
+            Sync::Always | Sync::Interval(_) => self.writer.sync()?,
+            Sync::Never => self.writer.flush()?,
+        }
+        let compaction_gen = self.current_gen + 1;
+        self.writer = new_log_file(&self.path, self.current_gen + 2)?;
+        self.current_gen += 2;
+        self.sync_file = Arc::new(self.writer.get_ref().try_clone()?);
+        if let Some(syncer) = &self.syncer {
+            syncer.set_file(self.writer.get_ref().try_clone()?);
+        }
+        Ok(compaction_gen)
+    }
+
+    /// Syncs the current log to the disk.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.sync()
+    }
+}
+
+/// Group commit of the writes in the `Sync::Always` mode.
+///
+/// Instead of syncing the log after every write, a writer waits for its bytes
+/// to be synced. The first waiter becomes the leader and syncs everything
+/// appended so far, while the writers arriving meanwhile keep appending and
+/// are acknowledged together by the next sync.
+#[derive(Default)]
+pub(super) struct GroupCommit {
+    state: Mutex<CommitState>,
+    synced: Condvar,
+}
+
+#[derive(Default)]
+struct CommitState {
+    // the log is durable up to this amount of appended bytes
+    synced: u64,
+    // a leader is syncing the log right now
+    syncing: bool,
+}
+
+impl GroupCommit {
+    /// Blocks until the log is synced past the given amount of appended bytes.
+    pub(super) fn wait(&self, ticket: u64, writer: &Mutex<LogWriter>) -> Result<()> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if state.synced >= ticket {
+                return Ok(());
+            }
+            if state.syncing {
+                state = self.synced.wait(state).unwrap();
+                continue;
+            }
+
+            // become the leader.
+            state.syncing = true;
+            drop(state);
+            let (target, file) = {
+                let writer = writer.lock().unwrap();
+                (writer.appended, Arc::clone(&writer.sync_file))
+            };
+            let res = file.sync_data();
+
+            state = self.state.lock().unwrap();
+            state.syncing = false;
+            if res.is_ok() {
+                state.synced = state.synced.max(target);
+            }
+            self.synced.notify_all();
+            res?;
+        }
+    }
+}
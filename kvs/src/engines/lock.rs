@@ -0,0 +1,76 @@
+//! Advisory locks keeping a data directory to a single open engine.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+
+use fs2::FileExt;
+
+use crate::{KvsError, Result};
+
+/// Name of the lock file of a data directory.
+const LOCK_FILE: &str = "kvs.lock";
+
+/// An advisory lock on a data directory, held until it is dropped.
+///
+/// The lock file records the PID of the owner. The lock itself is taken by
+/// the operating system, so it is released even if the owner crashes.
+pub(crate) struct DirLock {
+    _file: File,
+}
+
+impl DirLock {
+    /// Locks the data directory.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if another handle, in this
+    /// or another process, holds the lock.
+    ///
+    /// It propagates other I/O errors of taking the lock as `KvsError::Io`.
+    pub(crate) fn acquire(dir: &Path) -> Result<DirLock> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir.join(LOCK_FILE))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => {}
+            Err(e) if is_contended(&e) => {
+                return Err(KvsError::DirectoryLocked {
+                    pid: read_owner(&mut file)?,
+                });
+            }
+            Err(e) => return Err(e.into()),
+        }
+        file.set_len(0)?;
+        file.write_all(format!("{}\n", std::process::id()).as_bytes())?;
+        file.sync_data()?;
+        Ok(DirLock { _file: file })
+    }
+}
+
+/// Tells whether the error of `try_lock_exclusive` means that the lock is held.
+fn is_contended(e: &io::Error) -> bool {
+    e.raw_os_error() == fs2::lock_contended_error().raw_os_error()
+}
+
+/// Reads the PID of the owner of the lock.
+///
+/// The owner writes it right after taking the lock, so it is waited for
+/// a little while.
+fn read_owner(file: &mut File) -> Result<u32> {
+    for _ in 0..50 {
+        let mut content = String::new();
+        file.seek(SeekFrom::Start(0))?;
+        file.read_to_string(&mut content)?;
+        if let Ok(pid) = content.trim().parse() {
+            return Ok(pid);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    Err(io::Error::new(io::ErrorKind::WouldBlock, "data directory is locked").into())
+}
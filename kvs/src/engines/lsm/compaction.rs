@@ -0,0 +1,268 @@
+//! Background leveled compaction of the `LsmEngine` tables.
+//!
+//! Level 0 holds the flushed memtables, which may overlap. Once it holds
+//! enough tables, all of them are merged with the overlapping tables of
+//! level 1. The tables of every deeper level are disjoint, and once a level
+//! grows beyond its size, one of its tables, picked round-robin by key, is
+//! merged with the overlapping tables of the next level.
+
+use std::collections::HashSet;
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use super::entry::Entry;
+use super::merge::{MergeIter, Source};
+use super::sstable::{Table, TableBuilder, TableIter};
+use super::{Shared, MAX_LEVELS};
+use crate::engines::now_millis;
+use crate::Result;
+
+/// Growth factor of the size of every level over the one above.
+const LEVEL_SIZE_MULTIPLIER: u64 = 10;
+
+/// A compaction of tables of a level into the next one.
+struct CompactionJob {
+    level: usize,
+    // the tables of the level, newest first
+    upper: Vec<Arc<Table>>,
+    // the overlapping tables of the next level
+    lower: Vec<Arc<Table>>,
+}
+
+/// Handle to the background compaction thread.
+///
+/// The thread compacts the levels which need it every time it is woken up.
+/// Dropping the handle waits for the running compaction to finish.
+pub(super) struct Compactor {
+    sender: Option<Sender<()>>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl Compactor {
+    /// Spawns the compaction thread of the engine.
+    pub(super) fn spawn(shared: Arc<Shared>) -> Result<Compactor> {
+        let (sender, receiver) = mpsc::channel();
+        let handle = thread::Builder::new()
+            .name("lsm-compaction".to_owned())
+            .spawn(move || run(&shared, receiver))?;
+        Ok(Compactor {
+            sender: Some(sender),
+            handle: Some(handle),
+        })
+    }
+
+    /// Asks the thread to compact the levels which need it.
+    pub(super) fn wake(&self) {
+        if let Some(sender) = &self.sender {
+            // the thread only exits once the sender is dropped.
+            let _ = sender.send(());
+        }
+    }
+}
+
+impl Drop for Compactor {
+    fn drop(&mut self) {
+        // closing the channel stops the thread after the running compaction.
+        self.sender.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn run(shared: &Shared, receiver: Receiver<()>) {
+    // the largest key compacted last out of every level
+    let mut pointers: Vec<Option<Vec<u8>>> = vec![None; MAX_LEVELS];
+    while receiver.recv().is_ok() {
+        loop {
+            match receiver.try_recv() {
+                Err(TryRecvError::Disconnected) => return,
+                Ok(()) | Err(TryRecvError::Empty) => {}
+            }
+            // A failed compaction leaves the tables untouched,
+            // so it is simply picked again once the thread is woken up.
+            match compact(shared, &mut pointers) {
+                Ok(true) => {}
+                Ok(false) | Err(_) => break,
+            }
+        }
+    }
+}
+
+/// Runs the compaction of the first level which needs one.
+///
+/// Returns `false` if no level needs a compaction.
+fn compact(shared: &Shared, pointers: &mut [Option<Vec<u8>>]) -> Result<bool> {
+    let levels = shared.state().levels.clone();
+    let job = match pick(shared, &levels, pointers) {
+        Some(job) => job,
+        None => return Ok(false),
+    };
+    if let Some(largest) = job.upper.iter().map(|table| table.largest()).max() {
+        pointers[job.level] = Some(largest.to_vec());
+    }
+
+    let level = job.level;
+    let upper_ids: HashSet<u64> = job.upper.iter().map(|table| table.id()).collect();
+    let lower_ids: HashSet<u64> = job.lower.iter().map(|table| table.id()).collect();
+    if level > 0 && job.upper.len() == 1 && job.lower.is_empty() {
+        // nothing to merge with, the table simply moves down.
+        let table = Arc::clone(&job.upper[0]);
+        shared.install(
+            |levels| {
+                levels[level].retain(|table| !upper_ids.contains(&table.id()));
+                insert_sorted(&mut levels[level + 1], vec![table]);
+            },
+            None,
+        )?;
+        return Ok(true);
+    }
+
+    let mut outputs = Vec::new();
+    let res = merge(shared, &job, &levels[level + 2..], &mut outputs).and_then(|()| {
+        shared.install(
+            |levels| {
+                levels[level].retain(|table| !upper_ids.contains(&table.id()));
+                levels[level + 1].retain(|table| !lower_ids.contains(&table.id()));
+                insert_sorted(&mut levels[level + 1], outputs.clone());
+            },
+            None,
+        )
+    });
+    // the tables which are not in the manifest are removed once dropped.
+    let replaced: Vec<_> = match res {
+        Ok(()) => job.upper.iter().chain(&job.lower).collect(),
+        Err(_) => outputs.iter().collect(),
+    };
+    for table in replaced {
+        table.mark_obsolete();
+    }
+    res.map(|()| true)
+}
+
+/// Picks the next compaction: level 0 if it holds too many tables, or else
+/// the first level grown beyond its size.
+fn pick(
+    shared: &Shared,
+    levels: &[Vec<Arc<Table>>],
+    pointers: &[Option<Vec<u8>>],
+) -> Option<CompactionJob> {
+    let options = &shared.options;
+    if levels[0].len() >= options.level0_tables.max(1) {
+        let upper = levels[0].clone();
+        let smallest = upper.iter().map(|table| table.smallest()).min()?;
+        let largest = upper.iter().map(|table| table.largest()).max()?;
+        let lower = overlapping(levels.get(1), smallest, largest);
+        return Some(CompactionJob {
+            level: 0,
+            upper,
+            lower,
+        });
+    }
+    let mut max_size = options.level_base_size;
+    // the last level grows without limit.
+    for (level, pointer) in pointers.iter().enumerate().take(MAX_LEVELS - 1).skip(1) {
+        let tables = levels.get(level)?;
+        let size: u64 = tables.iter().map(|table| table.size()).sum();
+        if size > max_size {
+            let table = tables
+                .iter()
+                .find(|table| {
+                    pointer
+                        .as_ref()
+                        .map_or(true, |pointer| table.smallest() > pointer.as_slice())
+                })
+                .unwrap_or(&tables[0]);
+            let lower = overlapping(levels.get(level + 1), table.smallest(), table.largest());
+            return Some(CompactionJob {
+                level,
+                upper: vec![Arc::clone(table)],
+                lower,
+            });
+        }
+        max_size = max_size.saturating_mul(LEVEL_SIZE_MULTIPLIER);
+    }
+    None
+}
+
+/// Merges the tables of the job into new tables of the next level, added
+/// to `outputs` as they are written.
+///
+/// Tombstones and expired entries are dropped unless a deeper level may
+/// hold an older entry of the key, which they still have to hide.
+fn merge(
+    shared: &Shared,
+    job: &CompactionJob,
+    deeper: &[Vec<Arc<Table>>],
+    outputs: &mut Vec<Arc<Table>>,
+) -> Result<()> {
+    let mut sources: Vec<Source> = Vec::new();
+    for table in &job.upper {
+        sources.push(Box::new(full_iter(table)));
+    }
+    let lower = job.lower.clone();
+    sources.push(Box::new(
+        lower.into_iter().flat_map(|table| full_iter(&table)),
+    ));
+
+    let mut builder: Option<TableBuilder> = None;
+    for pair in MergeIter::new(sources) {
+        let (key, mut entry) = pair?;
+        if entry.is_dead(now_millis()) {
+            if !may_hold(deeper, &key) {
+                continue;
+            }
+            entry = Entry::tombstone();
+        }
+        let table = match &mut builder {
+            Some(builder) => builder,
+            None => builder.insert(TableBuilder::create(
+                &shared.dir,
+                shared.allocate_id(),
+                shared.options.block_size,
+            )?),
+        };
+        table.add(&key, &entry)?;
+        if table.size() >= shared.options.table_size {
+            outputs.push(Arc::new(builder.take().unwrap().finish()?));
+        }
+    }
+    if let Some(builder) = builder {
+        outputs.push(Arc::new(builder.finish()?));
+    }
+    Ok(())
+}
+
+fn full_iter(table: &Arc<Table>) -> TableIter {
+    use std::ops::Bound::Unbounded;
+    TableIter::new(Arc::clone(table), Unbounded, Unbounded)
+}
+
+/// Returns `true` if any of the levels has a table which may hold the key.
+fn may_hold(levels: &[Vec<Arc<Table>>], key: &[u8]) -> bool {
+    levels.iter().any(|tables| {
+        let i = tables.partition_point(|table| table.largest() < key);
+        tables.get(i).is_some_and(|table| table.smallest() <= key)
+    })
+}
+
+/// Returns the tables of a level overlapping the key range.
+fn overlapping(
+    tables: Option<&Vec<Arc<Table>>>,
+    smallest: &[u8],
+    largest: &[u8],
+) -> Vec<Arc<Table>> {
+    tables
+        .into_iter()
+        .flatten()
+        .filter(|table| table.largest() >= smallest && table.smallest() <= largest)
+        .cloned()
+        .collect()
+}
+
+/// Adds the tables to a level, keeping it in the order of the keys.
+fn insert_sorted(level: &mut Vec<Arc<Table>>, tables: Vec<Arc<Table>>) {
+    level.extend(tables);
+    level.sort_by(|a, b| a.smallest().cmp(b.smallest()));
+}
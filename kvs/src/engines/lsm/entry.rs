@@ -0,0 +1,124 @@
+//! Entries of the `LsmEngine` and their binary encoding, shared by the
+//! write-ahead log and the sorted tables.
+//!
+//! An entry is encoded as a little-endian `u32` key length, the key, a flags
+//! byte, the little-endian `u64` expiry time if flagged, and the little-endian
+//! `u32` value length followed by the value unless the entry is a tombstone.
+
+use std::convert::TryInto;
+
+/// Flag of an entry holding a value rather than a tombstone.
+const VALUE_FLAG: u8 = 1;
+/// Flag of an entry followed by its expiry time.
+const EXPIRY_FLAG: u8 = 1 << 1;
+
+/// A value or a tombstone of a removed key, along with its expiry time.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(super) struct Entry {
+    pub(super) value: Option<Vec<u8>>,
+    // milliseconds since the unix epoch
+    pub(super) expires_at: Option<u64>,
+}
+
+impl Entry {
+    /// Creates an entry holding the value.
+    pub(super) fn value(value: Vec<u8>, expires_at: Option<u64>) -> Entry {
+        Entry {
+            value: Some(value),
+            expires_at,
+        }
+    }
+
+    /// Creates the tombstone of a removed key.
+    pub(super) fn tombstone() -> Entry {
+        Entry {
+            value: None,
+            expires_at: None,
+        }
+    }
+
+    /// Returns `true` if the entry hides the key: it is a tombstone or
+    /// it has expired by `now`.
+    pub(super) fn is_dead(&self, now: u64) -> bool {
+        self.value.is_none() || self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+
+    /// Returns the value unless the entry is dead by `now`.
+    pub(super) fn into_live_value(self, now: u64) -> Option<Vec<u8>> {
+        if self.is_dead(now) {
+            None
+        } else {
+            self.value
+        }
+    }
+
+    /// Returns the number of bytes the entry of the key is encoded with.
+    pub(super) fn encoded_len(&self, key: &[u8]) -> usize {
+        let expiry_len = if self.expires_at.is_some() { 8 } else { 0 };
+        let value_len = self.value.as_ref().map_or(0, |value| 4 + value.len());
+        4 + key.len() + 1 + expiry_len + value_len
+    }
+}
+
+/// Appends the encoded entry of the key to the buffer.
+pub(super) fn encode_entry(buf: &mut Vec<u8>, key: &[u8], entry: &Entry) {
+    buf.extend_from_slice(&(key.len() as u32).to_le_bytes());
+    buf.extend_from_slice(key);
+    let mut flags = 0;
+    if entry.value.is_some() {
+        flags |= VALUE_FLAG;
+    }
+    if entry.expires_at.is_some() {
+        flags |= EXPIRY_FLAG;
+    }
+    buf.push(flags);
+    if let Some(expires_at) = entry.expires_at {
+        buf.extend_from_slice(&expires_at.to_le_bytes());
+    }
+    if let Some(value) = &entry.value {
+        buf.extend_from_slice(&(value.len() as u32).to_le_bytes());
+        buf.extend_from_slice(value);
+    }
+}
+
+/// Decodes an entry from the front of the buffer, advancing it.
+///
+/// Returns `None` if the buffer does not start with a valid entry.
+pub(super) fn decode_entry(buf: &mut &[u8]) -> Option<(Vec<u8>, Entry)> {
+    let key_len = read_u32(buf)? as usize;
+    let key = read_bytes(buf, key_len)?.to_vec();
+    let (&flags, rest) = buf.split_first()?;
+    *buf = rest;
+    let expires_at = if flags & EXPIRY_FLAG != 0 {
+        Some(read_u64(buf)?)
+    } else {
+        None
+    };
+    let value = if flags & VALUE_FLAG != 0 {
+        let value_len = read_u32(buf)? as usize;
+        Some(read_bytes(buf, value_len)?.to_vec())
+    } else {
+        None
+    };
+    Some((key, Entry { value, expires_at }))
+}
+
+/// Reads a little-endian `u32` from the front of the buffer, advancing it.
+pub(super) fn read_u32(buf: &mut &[u8]) -> Option<u32> {
+    read_bytes(buf, 4).map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Reads a little-endian `u64` from the front of the buffer, advancing it.
+pub(super) fn read_u64(buf: &mut &[u8]) -> Option<u64> {
+    read_bytes(buf, 8).map(|bytes| u64::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+/// Splits `len` bytes off the front of the buffer.
+pub(super) fn read_bytes<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(bytes)
+}
@@ -0,0 +1,58 @@
+//! Manifest of the `LsmEngine`: its tables and its current write-ahead log.
+
+use std::fs::{self, File};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+/// Name of the file listing the tables of every level.
+const MANIFEST_FILE: &str = "lsm.manifest";
+
+/// The tables making up the `LsmEngine` data, along with the write-ahead
+/// log of the current memtable.
+///
+/// The manifest is rewritten whole after every flush and compaction, so
+/// tables and logs which are not listed in it are leftovers of an interrupted
+/// one.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(super) struct Manifest {
+    /// Ids of the tables of every level: the tables of level 0 may overlap
+    /// and are listed newest first, the tables of the deeper levels are
+    /// disjoint and listed in the order of their keys.
+    pub(super) levels: Vec<Vec<u64>>,
+    /// Id of the oldest write-ahead log not flushed to a table yet.
+    pub(super) wal_id: u64,
+    /// Id the next table or log file is created with.
+    pub(super) next_id: u64,
+}
+
+impl Manifest {
+    /// Reads the manifest of the data directory, or returns an empty one if
+    /// the directory has none yet.
+    pub(super) fn load(dir: &Path) -> Result<Manifest> {
+        match File::open(manifest_path(dir)) {
+            Ok(file) => Ok(serde_json::from_reader(io::BufReader::new(file))?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Writes the manifest to the data directory.
+    ///
+    /// The file is written under a temporary name and renamed, so the
+    /// manifest is swapped atomically.
+    pub(super) fn save(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", MANIFEST_FILE));
+        fs::write(&tmp_path, serde_json::to_vec(self)?)?;
+        File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, manifest_path(dir))?;
+        Ok(())
+    }
+}
+
+fn manifest_path(dir: &Path) -> PathBuf {
+    dir.join(MANIFEST_FILE)
+}
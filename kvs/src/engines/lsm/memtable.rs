@@ -0,0 +1,90 @@
+use std::cmp::Reverse;
+use std::ops::Bound;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+use crossbeam_skiplist::SkipMap;
+
+use super::entry::Entry;
+
+/// The latest writes of the `LsmEngine`, kept in memory until they are
+/// flushed to a sorted table.
+///
+/// Every write is a new version of its key, ordered newest first, so
+/// a written key never disappears from the skip list for a moment, as it
+/// would while an existing entry is replaced.
+pub(super) struct Memtable {
+    entries: SkipMap<(Vec<u8>, Reverse<u64>), Entry>,
+    next_seq: AtomicU64,
+    // the encoded size of the entries
+    size: AtomicUsize,
+}
+
+impl Memtable {
+    /// Creates an empty memtable.
+    pub(super) fn new() -> Memtable {
+        Memtable {
+            entries: SkipMap::new(),
+            next_seq: AtomicU64::new(0),
+            size: AtomicUsize::new(0),
+        }
+    }
+
+    /// Adds the latest version of a key.
+    ///
+    /// Must be called under the writer lock.
+    pub(super) fn insert(&self, key: Vec<u8>, entry: Entry) {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        self.size
+            .fetch_add(entry.encoded_len(&key), Ordering::SeqCst);
+        self.entries.insert((key, Reverse(seq)), entry);
+    }
+
+    /// Returns the latest version of a key, if the memtable has any.
+    pub(super) fn get(&self, key: &[u8]) -> Option<Entry> {
+        let start = (key.to_vec(), Reverse(u64::MAX));
+        let found = self.entries.range(start..).next()?;
+        if found.key().0 == key {
+            Some(found.value().clone())
+        } else {
+            None
+        }
+    }
+
+    /// Returns the latest version of every key within the range, in the
+    /// order of the keys.
+    pub(super) fn range(
+        &self,
+        start: &Bound<Vec<u8>>,
+        end: &Bound<Vec<u8>>,
+    ) -> Vec<(Vec<u8>, Entry)> {
+        let start = match start {
+            Bound::Included(key) => Bound::Included((key.clone(), Reverse(u64::MAX))),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), Reverse(0))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let end = match end {
+            Bound::Included(key) => Bound::Included((key.clone(), Reverse(0))),
+            Bound::Excluded(key) => Bound::Excluded((key.clone(), Reverse(u64::MAX))),
+            Bound::Unbounded => Bound::Unbounded,
+        };
+        let mut entries: Vec<(Vec<u8>, Entry)> = Vec::new();
+        for found in self.entries.range((start, end)) {
+            let key = &found.key().0;
+            // the first version of a key is the latest one.
+            if entries.last().map_or(true, |(last, _)| last != key) {
+                entries.push((key.clone(), found.value().clone()));
+            }
+        }
+        entries
+    }
+
+    /// Returns the encoded size of the entries.
+    pub(super) fn size(&self) -> usize {
+        self.size.load(Ordering::SeqCst)
+    }
+
+    /// Returns `true` if nothing has been written to the memtable.
+    pub(super) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
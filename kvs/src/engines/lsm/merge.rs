@@ -0,0 +1,91 @@
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use super::entry::Entry;
+use crate::{KvsError, Result};
+
+/// A sorted run of entries: a memtable, a table or a level of tables.
+pub(super) type Source<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Entry)>> + 'a>;
+
+/// Merges sorted runs of entries into one, in the order of the keys.
+///
+/// The runs are given newest first: when several of them hold a key, only
+/// the entry of the newest one is returned. Tombstones and expired entries
+/// are returned too, it is up to the caller to skip them.
+pub(super) struct MergeIter<'a> {
+    sources: Vec<Source<'a>>,
+    // the next key of every source, along with its position, so the
+    // newest source comes first among equal keys
+    heap: BinaryHeap<Reverse<(Vec<u8>, usize)>>,
+    heads: Vec<Option<Entry>>,
+    // the error of a source, returned before the merge ends
+    error: Option<KvsError>,
+    failed: bool,
+}
+
+impl<'a> MergeIter<'a> {
+    /// Creates the merge of the sources, ordered newest first.
+    pub(super) fn new(sources: Vec<Source<'a>>) -> MergeIter<'a> {
+        let heads = sources.iter().map(|_| None).collect();
+        let mut merge = MergeIter {
+            sources,
+            heap: BinaryHeap::new(),
+            heads,
+            error: None,
+            failed: false,
+        };
+        for i in 0..merge.sources.len() {
+            if let Err(e) = merge.advance(i) {
+                merge.error = Some(e);
+                break;
+            }
+        }
+        merge
+    }
+
+    /// Pulls the next entry of the source into the heap.
+    fn advance(&mut self, i: usize) -> Result<()> {
+        if let Some(pair) = self.sources[i].next() {
+            let (key, entry) = pair?;
+            self.heads[i] = Some(entry);
+            self.heap.push(Reverse((key, i)));
+        }
+        Ok(())
+    }
+}
+
+impl Iterator for MergeIter<'_> {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        if let Some(e) = self.error.take() {
+            self.failed = true;
+            return Some(Err(e));
+        }
+        let Reverse((key, i)) = self.heap.pop()?;
+        let entry = self.heads[i]
+            .take()
+            .expect("a source in the heap has a head");
+        let mut res = self.advance(i);
+        // the same key in the older sources is shadowed.
+        while res.is_ok() {
+            match self.heap.peek() {
+                Some(Reverse((next, _))) if *next == key => {}
+                _ => break,
+            }
+            let Reverse((_, j)) = self.heap.pop().unwrap();
+            self.heads[j] = None;
+            res = self.advance(j);
+        }
+        match res {
+            Ok(()) => Some(Ok((key, entry))),
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
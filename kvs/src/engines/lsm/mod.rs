@@ -0,0 +1,503 @@
+//! `LsmEngine`, the log-structured merge-tree engine.
+
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::fs;
+use std::ops::{Bound, RangeBounds};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, RwLock};
+use std::time::Duration;
+
+use self::compaction::Compactor;
+use self::entry::Entry;
+use self::manifest::Manifest;
+use self::memtable::Memtable;
+use self::merge::{MergeIter, Source};
+pub use self::options::LsmOptions;
+use self::sstable::{Table, TableBuilder, TableIter};
+use self::wal::{replay, wal_path, Wal};
+use super::{
+    check_removes, expiry_time, now_millis, time_left, BatchOp, DirLock, KvEngine, Scan, Watcher,
+    Watchers, WriteBatch,
+};
+use crate::{KvsError, Result};
+
+mod compaction;
+mod entry;
+mod manifest;
+mod memtable;
+mod merge;
+mod options;
+mod sstable;
+mod wal;
+
+/// Number of levels of tables.
+const MAX_LEVELS: usize = 7;
+
+/// The memtable and the tables of every level, replaced as a whole by
+/// every flush and compaction, so a reader always sees a consistent set.
+struct State {
+    memtable: Arc<Memtable>,
+    levels: Vec<Vec<Arc<Table>>>,
+}
+
+/// State shared between the `LsmEngine` handles and its compaction thread.
+struct Shared {
+    dir: PathBuf,
+    options: LsmOptions,
+    state: RwLock<Arc<State>>,
+    // serializes the writes, so the checks of a write are atomic with it
+    writer: Mutex<Wal>,
+    // serializes the changes of the tables by the flushes and compactions
+    manifest: Mutex<Manifest>,
+    next_id: AtomicU64,
+    watchers: Watchers,
+}
+
+impl Shared {
+    /// Returns the current memtable and tables.
+    fn state(&self) -> Arc<State> {
+        Arc::clone(&self.state.read().unwrap())
+    }
+
+    /// Returns a fresh id for a table or log file.
+    fn allocate_id(&self) -> u64 {
+        self.next_id.fetch_add(1, Ordering::SeqCst)
+    }
+
+    /// Returns the latest entry of a key, which may be a tombstone.
+    fn entry(&self, key: &[u8]) -> Result<Option<Entry>> {
+        let state = self.state();
+        if let Some(entry) = state.memtable.get(key) {
+            return Ok(Some(entry));
+        }
+        // the tables of level 0 may overlap, the newest one comes first.
+        for table in &state.levels[0] {
+            if let Some(entry) = table.get(key)? {
+                return Ok(Some(entry));
+            }
+        }
+        for tables in &state.levels[1..] {
+            let i = tables.partition_point(|table| table.largest() < key);
+            if let Some(table) = tables.get(i) {
+                if let Some(entry) = table.get(key)? {
+                    return Ok(Some(entry));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns the value of a key unless it is missing, removed or expired.
+    fn live_value(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .entry(key)?
+            .and_then(|entry| entry.into_live_value(now_millis())))
+    }
+
+    /// Edits the tables of the levels and, for a flush, swaps the memtable
+    /// for an empty one backed by the given log, then records the tables
+    /// in the manifest and publishes them to the readers.
+    fn install(
+        &self,
+        edit: impl FnOnce(&mut Vec<Vec<Arc<Table>>>),
+        flushed_to_wal: Option<u64>,
+    ) -> Result<()> {
+        let mut manifest = self.manifest.lock().unwrap();
+        let current = self.state();
+        let mut levels = current.levels.clone();
+        edit(&mut levels);
+
+        let mut edited = manifest.clone();
+        edited.levels = levels
+            .iter()
+            .map(|tables| tables.iter().map(|table| table.id()).collect())
+            .collect();
+        if let Some(wal_id) = flushed_to_wal {
+            edited.wal_id = wal_id;
+        }
+        edited.next_id = self.next_id.load(Ordering::SeqCst);
+        edited.save(&self.dir)?;
+        *manifest = edited;
+
+        let memtable = match flushed_to_wal {
+            Some(_) => Arc::new(Memtable::new()),
+            None => Arc::clone(&current.memtable),
+        };
+        *self.state.write().unwrap() = Arc::new(State { memtable, levels });
+        Ok(())
+    }
+
+    /// Writes the memtable to a new level 0 table and starts a new log.
+    ///
+    /// Must be called under the writer lock.
+    fn flush_memtable(&self, wal: &mut Wal) -> Result<()> {
+        let memtable = Arc::clone(&self.state().memtable);
+        if memtable.is_empty() {
+            return Ok(());
+        }
+        let mut builder =
+            TableBuilder::create(&self.dir, self.allocate_id(), self.options.block_size)?;
+        for (key, entry) in memtable.range(&Bound::Unbounded, &Bound::Unbounded) {
+            builder.add(&key, &entry)?;
+        }
+        let table = Arc::new(builder.finish()?);
+        let new_wal = Wal::create(&self.dir, self.allocate_id())?;
+        let new_wal_id = new_wal.id();
+        let res = self.install(
+            |levels| levels[0].insert(0, Arc::clone(&table)),
+            Some(new_wal_id),
+        );
+        if let Err(e) = res {
+            table.mark_obsolete();
+            let _ = fs::remove_file(wal_path(&self.dir, new_wal_id));
+            return Err(e);
+        }
+        let old_wal = std::mem::replace(wal, new_wal);
+        // a log left behind is removed when the directory is opened again.
+        let _ = fs::remove_file(wal_path(&self.dir, old_wal.id()));
+        Ok(())
+    }
+}
+
+/// A `KvEngine` built as a log-structured merge tree, for data sets whose
+/// keys do not fit in memory.
+///
+/// Writes go to a write-ahead log and to the memtable, a skip list in memory.
+/// Once the memtable is full, the next write flushes it to a sorted table file
+/// on level 0. Tables are split into blocks and only their block indexes are
+/// kept in memory. A background thread merges the tables into the deeper
+/// levels with leveled compaction, dropping overwritten and removed entries.
+///
+/// Reads look up the memtable, then the tables level by level, and never wait
+/// for writes or compactions. Expired keys are hidden from reads and dropped
+/// by the compactions.
+///
+/// Every write reaches the operating system before it returns, so it survives
+/// a crash of the process, and `KvEngine::flush` syncs the log to the disk.
+///
+/// ```rust
+/// # use kvs::{KvEngine, LsmEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let engine = LsmEngine::open(current_dir()?)?;
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct LsmEngine {
+    shared: Arc<Shared>,
+    compactor: Arc<Compactor>,
+    // released once the last handle is dropped, after the compaction thread
+    // has stopped
+    _lock: Arc<DirLock>,
+}
+
+impl LsmEngine {
+    /// Opens an `LsmEngine` with the given path and the default options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    /// The writes left in the logs by the previous run are flushed to a table.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if the directory is already
+    /// opened, by this or another process.
+    ///
+    /// It returns `KvsError::Corruption` if a log record is damaged. A record
+    /// cut off at the end of a log is discarded instead.
+    ///
+    /// It propagates I/O errors, including the ones of a damaged table.
+    pub fn open(path: impl Into<PathBuf>) -> Result<LsmEngine> {
+        LsmEngine::open_with(path, &LsmOptions::new())
+    }
+
+    /// Opens an `LsmEngine` with the given path and options.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// Same as `LsmEngine::open`.
+    pub fn open_with(path: impl Into<PathBuf>, options: &LsmOptions) -> Result<LsmEngine> {
+        let dir = path.into();
+        fs::create_dir_all(&dir)?;
+        let lock = DirLock::acquire(&dir)?;
+
+        let mut manifest = Manifest::load(&dir)?;
+        manifest.levels.resize(MAX_LEVELS, Vec::new());
+        let mut levels = Vec::with_capacity(MAX_LEVELS);
+        for ids in &manifest.levels {
+            let tables = ids
+                .iter()
+                .map(|&id| Table::open(&dir, id).map(Arc::new))
+                .collect::<Result<Vec<_>>>()?;
+            levels.push(tables);
+        }
+
+        // files which are not in the manifest are the leftovers of an
+        // interrupted flush or compaction.
+        let listed: HashSet<u64> = manifest.levels.iter().flatten().copied().collect();
+        let mut next_id = manifest.next_id;
+        let mut wal_ids = Vec::new();
+        for entry in fs::read_dir(&dir)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(OsStr::to_str) {
+                Some(name) => name,
+                None => continue,
+            };
+            if name.ends_with(".sst.tmp") {
+                fs::remove_file(&path)?;
+            } else if let Some(id) = parse_id(name, ".sst") {
+                next_id = next_id.max(id + 1);
+                if !listed.contains(&id) {
+                    fs::remove_file(&path)?;
+                }
+            } else if let Some(id) = parse_id(name, ".wal") {
+                next_id = next_id.max(id + 1);
+                if id >= manifest.wal_id {
+                    wal_ids.push(id);
+                } else {
+                    fs::remove_file(&path)?;
+                }
+            }
+        }
+        wal_ids.sort_unstable();
+        let memtable = Memtable::new();
+        for &id in &wal_ids {
+            replay(&dir, id, &memtable)?;
+        }
+
+        let wal = Wal::create(&dir, next_id)?;
+        let shared = Arc::new(Shared {
+            dir,
+            options: options.clone(),
+            state: RwLock::new(Arc::new(State {
+                memtable: Arc::new(memtable),
+                levels,
+            })),
+            writer: Mutex::new(wal),
+            manifest: Mutex::new(manifest),
+            next_id: AtomicU64::new(next_id + 1),
+            watchers: Watchers::default(),
+        });
+        shared.flush_memtable(&mut shared.writer.lock().unwrap())?;
+        for id in wal_ids {
+            fs::remove_file(wal_path(&shared.dir, id))?;
+        }
+
+        let compactor = Compactor::spawn(Arc::clone(&shared))?;
+        // the levels may have been left in need of a compaction.
+        compactor.wake();
+        Ok(LsmEngine {
+            shared,
+            compactor: Arc::new(compactor),
+            _lock: Arc::new(lock),
+        })
+    }
+
+    /// Locks the writer, flushing the memtable first if it is full.
+    fn writer(&self) -> Result<MutexGuard<'_, Wal>> {
+        let mut wal = self.shared.writer.lock().unwrap();
+        if self.shared.state().memtable.size() >= self.shared.options.memtable_size {
+            self.shared.flush_memtable(&mut wal)?;
+            self.compactor.wake();
+        }
+        Ok(wal)
+    }
+
+    /// Logs the writes and applies them to the memtable, notifying the watchers.
+    ///
+    /// Must be called under the writer lock.
+    fn apply(&self, wal: &mut Wal, entries: Vec<(Vec<u8>, Entry)>) -> Result<()> {
+        if entries.is_empty() {
+            return Ok(());
+        }
+        wal.append(&entries)?;
+        let memtable = Arc::clone(&self.shared.state().memtable);
+        let events = self.shared.watchers.events(
+            entries
+                .iter()
+                .map(|(key, entry)| (key.as_slice(), entry.value.as_deref())),
+        );
+        for (key, entry) in entries {
+            memtable.insert(key, entry);
+        }
+        self.shared.watchers.notify(events);
+        Ok(())
+    }
+}
+
+impl KvEngine for LsmEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut wal = self.writer()?;
+        self.apply(&mut wal, vec![(key, Entry::value(value, None))])
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_time(ttl);
+        let mut wal = self.writer()?;
+        self.apply(&mut wal, vec![(key, Entry::value(value, Some(expires_at)))])
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        self.shared.live_value(&key)
+    }
+
+    fn get_bytes_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        let now = now_millis();
+        Ok(self.shared.entry(&key)?.and_then(|entry| {
+            let ttl = entry.expires_at.map(|at| time_left(at, now));
+            entry.into_live_value(now).map(|value| (value, ttl))
+        }))
+    }
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// The scan reads the memtable and the tables as they were when it
+    /// started. The tables are read block by block as the iterator advances.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>> {
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let state = self.shared.state();
+
+        let mut sources: Vec<Source> = Vec::new();
+        sources.push(Box::new(
+            state.memtable.range(&start, &end).into_iter().map(Ok),
+        ));
+        for table in &state.levels[0] {
+            if table.overlaps(&start, &end) {
+                sources.push(Box::new(TableIter::new(
+                    Arc::clone(table),
+                    start.clone(),
+                    end.clone(),
+                )));
+            }
+        }
+        for tables in &state.levels[1..] {
+            let tables: Vec<_> = tables
+                .iter()
+                .filter(|table| table.overlaps(&start, &end))
+                .cloned()
+                .collect();
+            if tables.is_empty() {
+                continue;
+            }
+            let (start, end) = (start.clone(), end.clone());
+            sources.push(Box::new(tables.into_iter().flat_map(move |table| {
+                TableIter::new(table, start.clone(), end.clone())
+            })));
+        }
+
+        Ok(Box::new(MergeIter::new(sources).filter_map(|pair| {
+            match pair {
+                Ok((key, entry)) => entry
+                    .into_live_value(now_millis())
+                    .map(|value| Ok((key, value))),
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let mut wal = self.writer()?;
+        if self.shared.live_value(&key)?.is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.apply(&mut wal, vec![(key, Entry::tombstone())])
+    }
+
+    /// Sets or removes a key if its current value is the expected one.
+    ///
+    /// The check and the write happen under the writer lock, so no other
+    /// write can come in between.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut wal = self.writer()?;
+        let current = self.shared.live_value(&key)?;
+        if current != expected {
+            return Ok(false);
+        }
+        let entry = match (current, new) {
+            (_, Some(value)) => Entry::value(value, None),
+            (Some(_), None) => Entry::tombstone(),
+            (None, None) => return Ok(true),
+        };
+        self.apply(&mut wal, vec![(key, entry)])?;
+        Ok(true)
+    }
+
+    /// Applies all the writes of the batch atomically, logged as a single
+    /// record.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let mut wal = self.writer()?;
+        check_removes(&batch, |key| Ok(self.shared.live_value(key)?.is_some()))?;
+        self.apply(&mut wal, batch_entries(batch))
+    }
+
+    /// Applies the batch if the read keys still hold the values they were
+    /// read with, checked under the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn transact(&self, reads: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch) -> Result<()> {
+        let mut wal = self.writer()?;
+        for (key, value) in &reads {
+            if self.shared.live_value(key)? != *value {
+                return Err(KvsError::TxnConflict);
+            }
+        }
+        check_removes(&batch, |key| Ok(self.shared.live_value(key)?.is_some()))?;
+        self.apply(&mut wal, batch_entries(batch))
+    }
+
+    /// Watches the writes to the keys starting with the prefix.
+    ///
+    /// The events are sent under the writer lock, so they come in the order
+    /// of the writes.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        Ok(self.shared.watchers.subscribe(prefix))
+    }
+
+    /// Syncs the log of the memtable to the disk.
+    fn flush(&self) -> Result<()> {
+        self.shared.writer.lock().unwrap().sync()
+    }
+}
+
+/// Returns the entries written by the batch.
+fn batch_entries(batch: WriteBatch) -> Vec<(Vec<u8>, Entry)> {
+    batch
+        .into_iter()
+        .map(|op| match op {
+            BatchOp::Set { key, value } => (key, Entry::value(value, None)),
+            BatchOp::Remove { key } => (key, Entry::tombstone()),
+        })
+        .collect()
+}
+
+/// Parses the id of a file named after it with the given extension.
+fn parse_id(name: &str, extension: &str) -> Option<u64> {
+    name.strip_suffix(extension)?.parse().ok()
+}
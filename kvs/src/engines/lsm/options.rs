@@ -0,0 +1,98 @@
+/// Default size of the memtable which triggers a flush to a table.
+const DEFAULT_MEMTABLE_SIZE: usize = 4 * 1024 * 1024;
+
+/// Default size of the data blocks of the tables.
+const DEFAULT_BLOCK_SIZE: usize = 4 * 1024;
+
+/// Default size of the tables written by the compactions.
+const DEFAULT_TABLE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Default number of level 0 tables which triggers their compaction.
+const DEFAULT_LEVEL0_TABLES: usize = 4;
+
+/// Default size of level 1.
+const DEFAULT_LEVEL_BASE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Options to open an `LsmEngine` with.
+///
+/// ```rust
+/// # use kvs::{LsmEngine, LsmOptions, Result};
+/// # fn try_main() -> Result<()> {
+/// use std::env::current_dir;
+/// let mut options = LsmOptions::new();
+/// options
+///     .memtable_size(64 * 1024 * 1024)
+///     .table_size(32 * 1024 * 1024);
+/// let engine = LsmEngine::open_with(current_dir()?, &options)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone, Debug)]
+pub struct LsmOptions {
+    pub(super) memtable_size: usize,
+    pub(super) block_size: usize,
+    pub(super) table_size: u64,
+    pub(super) level0_tables: usize,
+    pub(super) level_base_size: u64,
+}
+
+impl LsmOptions {
+    /// Creates the default options: the memtable is flushed at 4MB, tables
+    /// are made of 4KB blocks and split at 2MB, level 0 is compacted once it
+    /// holds 4 tables and level 1 once it grows beyond 10MB, with every
+    /// deeper level ten times larger than the one above.
+    pub fn new() -> Self {
+        LsmOptions {
+            memtable_size: DEFAULT_MEMTABLE_SIZE,
+            block_size: DEFAULT_BLOCK_SIZE,
+            table_size: DEFAULT_TABLE_SIZE,
+            level0_tables: DEFAULT_LEVEL0_TABLES,
+            level_base_size: DEFAULT_LEVEL_BASE_SIZE,
+        }
+    }
+
+    /// Sets the size of the memtable which triggers a flush to a table.
+    ///
+    /// The memtable is flushed by the write which finds it full, so a larger
+    /// memtable makes fewer but longer pauses of the writes.
+    pub fn memtable_size(&mut self, size: usize) -> &mut Self {
+        self.memtable_size = size;
+        self
+    }
+
+    /// Sets the size of the data blocks of the tables.
+    ///
+    /// A lookup reads a single block, while the block index in memory holds
+    /// a key per block.
+    pub fn block_size(&mut self, size: usize) -> &mut Self {
+        self.block_size = size;
+        self
+    }
+
+    /// Sets the size the tables written by the compactions are split at.
+    pub fn table_size(&mut self, size: u64) -> &mut Self {
+        self.table_size = size;
+        self
+    }
+
+    /// Sets the number of level 0 tables which triggers their compaction
+    /// into level 1.
+    pub fn level0_tables(&mut self, tables: usize) -> &mut Self {
+        self.level0_tables = tables;
+        self
+    }
+
+    /// Sets the size of level 1 which triggers a compaction into level 2.
+    ///
+    /// Every deeper level may grow ten times larger than the one above.
+    pub fn level_base_size(&mut self, size: u64) -> &mut Self {
+        self.level_base_size = size;
+        self
+    }
+}
+
+impl Default for LsmOptions {
+    fn default() -> Self {
+        Self::new()
+    }
+}
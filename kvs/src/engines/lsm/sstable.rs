@@ -0,0 +1,412 @@
+//! Sorted table files of the `LsmEngine`.
+//!
+//! A table holds the entries of distinct keys in sorted order, split into
+//! data blocks of roughly the configured size, each followed by a CRC32
+//! checksum. The block index after the data blocks records the smallest key
+//! of the table and, for every block, its last key and position, so a lookup
+//! reads a single block. The file ends with a fixed footer: the position and
+//! length of the index, the number of entries and a magic number.
+//!
+//! Only the block indexes are kept in memory, one key per block.
+
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use super::entry::{decode_entry, encode_entry, read_bytes, read_u32, read_u64, Entry};
+use crate::Result;
+
+/// Magic number at the end of every table file.
+const MAGIC: u64 = 0x6b76_735f_6c73_6d31;
+/// Length of the footer: index offset, index length, entry count and magic.
+const FOOTER_LEN: usize = 32;
+
+/// Position of a data block, along with its last key.
+struct BlockHandle {
+    last_key: Vec<u8>,
+    offset: u64,
+    // including the checksum
+    len: u32,
+}
+
+/// Writes a new table file from entries added in the order of their keys.
+///
+/// The file is written under a temporary name and renamed once it is
+/// complete, so an interrupted write never leaves a truncated table behind.
+pub(super) struct TableBuilder {
+    dir: PathBuf,
+    id: u64,
+    writer: BufWriter<File>,
+    block_size: usize,
+    block: Vec<u8>,
+    index: Vec<BlockHandle>,
+    smallest: Option<Vec<u8>>,
+    last_key: Vec<u8>,
+    offset: u64,
+    count: u64,
+}
+
+impl TableBuilder {
+    /// Starts the table file with the given id.
+    pub(super) fn create(dir: &Path, id: u64, block_size: usize) -> Result<TableBuilder> {
+        let file = File::create(tmp_table_path(dir, id))?;
+        Ok(TableBuilder {
+            dir: dir.to_owned(),
+            id,
+            writer: BufWriter::new(file),
+            block_size,
+            block: Vec::new(),
+            index: Vec::new(),
+            smallest: None,
+            last_key: Vec::new(),
+            offset: 0,
+            count: 0,
+        })
+    }
+
+    /// Adds the entry of a key greater than all the keys added before.
+    pub(super) fn add(&mut self, key: &[u8], entry: &Entry) -> Result<()> {
+        if self.smallest.is_none() {
+            self.smallest = Some(key.to_vec());
+        }
+        encode_entry(&mut self.block, key, entry);
+        self.last_key.clear();
+        self.last_key.extend_from_slice(key);
+        self.count += 1;
+        if self.block.len() >= self.block_size {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    /// Returns the size the file has grown to so far.
+    pub(super) fn size(&self) -> u64 {
+        self.offset + self.block.len() as u64
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let checksum = crc32fast::hash(&self.block);
+        self.block.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&self.block)?;
+        self.index.push(BlockHandle {
+            last_key: self.last_key.clone(),
+            offset: self.offset,
+            len: self.block.len() as u32,
+        });
+        self.offset += self.block.len() as u64;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Writes the block index and the footer, syncs the file to the disk
+    /// and opens the complete table.
+    pub(super) fn finish(mut self) -> Result<Table> {
+        self.finish_block()?;
+        let smallest = self.smallest.take().unwrap_or_default();
+        let mut index = Vec::new();
+        index.extend_from_slice(&(smallest.len() as u32).to_le_bytes());
+        index.extend_from_slice(&smallest);
+        for handle in &self.index {
+            index.extend_from_slice(&(handle.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&handle.last_key);
+            index.extend_from_slice(&handle.offset.to_le_bytes());
+            index.extend_from_slice(&handle.len.to_le_bytes());
+        }
+        let checksum = crc32fast::hash(&index);
+        index.extend_from_slice(&checksum.to_le_bytes());
+        self.writer.write_all(&index)?;
+
+        self.writer.write_all(&self.offset.to_le_bytes())?;
+        self.writer.write_all(&(index.len() as u64).to_le_bytes())?;
+        self.writer.write_all(&self.count.to_le_bytes())?;
+        self.writer.write_all(&MAGIC.to_le_bytes())?;
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()?;
+
+        let path = table_path(&self.dir, self.id);
+        fs::rename(tmp_table_path(&self.dir, self.id), &path)?;
+        Table::open(&self.dir, self.id)
+    }
+}
+
+/// An open table file.
+///
+/// A table replaced by a compaction is marked obsolete, and its file is
+/// removed once the last reader drops it.
+pub(super) struct Table {
+    id: u64,
+    path: PathBuf,
+    file: File,
+    index: Vec<BlockHandle>,
+    smallest: Vec<u8>,
+    size: u64,
+    obsolete: AtomicBool,
+}
+
+impl Table {
+    /// Opens the table file with the given id, loading its block index.
+    pub(super) fn open(dir: &Path, id: u64) -> Result<Table> {
+        let path = table_path(dir, id);
+        let file = File::open(&path)?;
+        let size = file.metadata()?.len();
+        if size < FOOTER_LEN as u64 {
+            return Err(damaged(id));
+        }
+        let footer = read_at(&file, size - FOOTER_LEN as u64, FOOTER_LEN)?;
+        let mut rest = footer.as_slice();
+        let index_offset = read_u64(&mut rest).unwrap();
+        let index_len = read_u64(&mut rest).unwrap();
+        let _count = read_u64(&mut rest).unwrap();
+        let magic = read_u64(&mut rest).unwrap();
+        if magic != MAGIC
+            || index_len < 8
+            || index_offset.checked_add(index_len) != Some(size - FOOTER_LEN as u64)
+        {
+            return Err(damaged(id));
+        }
+        let index = read_at(&file, index_offset, index_len as usize)?;
+        let (smallest, index) = decode_index(&index).ok_or_else(|| damaged(id))?;
+        Ok(Table {
+            id,
+            path,
+            file,
+            index,
+            smallest,
+            size,
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Returns the id of the table file.
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the size of the table file.
+    pub(super) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Returns the smallest key of the table.
+    pub(super) fn smallest(&self) -> &[u8] {
+        &self.smallest
+    }
+
+    /// Returns the largest key of the table.
+    pub(super) fn largest(&self) -> &[u8] {
+        self.index.last().map_or(&[], |handle| &handle.last_key)
+    }
+
+    /// Returns `true` if the table may hold keys within the range.
+    pub(super) fn overlaps(&self, start: &Bound<Vec<u8>>, end: &Bound<Vec<u8>>) -> bool {
+        let after_start = match start {
+            Bound::Included(key) => self.largest() >= key.as_slice(),
+            Bound::Excluded(key) => self.largest() > key.as_slice(),
+            Bound::Unbounded => true,
+        };
+        let before_end = match end {
+            Bound::Included(key) => self.smallest() <= key.as_slice(),
+            Bound::Excluded(key) => self.smallest() < key.as_slice(),
+            Bound::Unbounded => true,
+        };
+        after_start && before_end
+    }
+
+    /// Returns the entry of a key, if the table holds any.
+    pub(super) fn get(&self, key: &[u8]) -> Result<Option<Entry>> {
+        if key < self.smallest() {
+            return Ok(None);
+        }
+        let i = self
+            .index
+            .partition_point(|handle| handle.last_key.as_slice() < key);
+        if i == self.index.len() {
+            return Ok(None);
+        }
+        Ok(self
+            .read_block(i)?
+            .into_iter()
+            .find(|(found, _)| found.as_slice() == key)
+            .map(|(_, entry)| entry))
+    }
+
+    /// Reads and checks the data block at the given position of the index.
+    fn read_block(&self, i: usize) -> Result<Vec<(Vec<u8>, Entry)>> {
+        let handle = &self.index[i];
+        let block = read_at(&self.file, handle.offset, handle.len as usize)?;
+        let (data, checksum) = block.split_at(block.len().saturating_sub(4));
+        let mut checksum = checksum;
+        if read_u32(&mut checksum) != Some(crc32fast::hash(data)) {
+            return Err(damaged(self.id));
+        }
+        let mut entries = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            entries.push(decode_entry(&mut rest).ok_or_else(|| damaged(self.id))?);
+        }
+        Ok(entries)
+    }
+
+    /// Marks the table as replaced, so its file is removed once it is dropped.
+    pub(super) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for Table {
+    fn drop(&mut self) {
+        if self.obsolete.load(Ordering::SeqCst) {
+            // a file left behind is removed when the directory is opened again.
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// Iterator over the entries of a table within a range, reading one block
+/// at a time.
+pub(super) struct TableIter {
+    table: Arc<Table>,
+    next_block: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, Entry)>,
+    start: Bound<Vec<u8>>,
+    end: Bound<Vec<u8>>,
+    done: bool,
+}
+
+impl TableIter {
+    /// Creates an iterator over the entries of the table within the range.
+    pub(super) fn new(table: Arc<Table>, start: Bound<Vec<u8>>, end: Bound<Vec<u8>>) -> TableIter {
+        // the first block which may hold a key within the range.
+        let next_block = match &start {
+            Bound::Included(key) => table.index.partition_point(|handle| handle.last_key < *key),
+            Bound::Excluded(key) => table
+                .index
+                .partition_point(|handle| handle.last_key <= *key),
+            Bound::Unbounded => 0,
+        };
+        TableIter {
+            table,
+            next_block,
+            entries: Vec::new().into_iter(),
+            start,
+            end,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for TableIter {
+    type Item = Result<(Vec<u8>, Entry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            let (key, entry) = match self.entries.next() {
+                Some(pair) => pair,
+                None if self.next_block < self.table.index.len() => {
+                    match self.table.read_block(self.next_block) {
+                        Ok(entries) => self.entries = entries.into_iter(),
+                        Err(e) => {
+                            self.done = true;
+                            return Some(Err(e));
+                        }
+                    }
+                    self.next_block += 1;
+                    continue;
+                }
+                None => break,
+            };
+            let after_start = match &self.start {
+                Bound::Included(start) => key >= *start,
+                Bound::Excluded(start) => key > *start,
+                Bound::Unbounded => true,
+            };
+            if !after_start {
+                continue;
+            }
+            let before_end = match &self.end {
+                Bound::Included(end) => key <= *end,
+                Bound::Excluded(end) => key < *end,
+                Bound::Unbounded => true,
+            };
+            if !before_end {
+                self.done = true;
+                break;
+            }
+            return Some(Ok((key, entry)));
+        }
+        None
+    }
+}
+
+/// Decodes the smallest key and the block handles of a block index.
+fn decode_index(index: &[u8]) -> Option<(Vec<u8>, Vec<BlockHandle>)> {
+    let (data, checksum) = index.split_at(index.len().checked_sub(4)?);
+    let mut checksum = checksum;
+    if read_u32(&mut checksum)? != crc32fast::hash(data) {
+        return None;
+    }
+    let mut rest = data;
+    let smallest_len = read_u32(&mut rest)? as usize;
+    let smallest = read_bytes(&mut rest, smallest_len)?.to_vec();
+    let mut handles = Vec::new();
+    while !rest.is_empty() {
+        let key_len = read_u32(&mut rest)? as usize;
+        let last_key = read_bytes(&mut rest, key_len)?.to_vec();
+        let offset = read_u64(&mut rest)?;
+        let len = read_u32(&mut rest)?;
+        handles.push(BlockHandle {
+            last_key,
+            offset,
+            len,
+        });
+    }
+    Some((smallest, handles))
+}
+
+/// Reads `len` bytes of the file at the given offset, without moving the
+/// cursor of the file, so concurrent readers share the handle.
+#[cfg(unix)]
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    use std::os::unix::fs::FileExt;
+    let mut buf = vec![0; len];
+    file.read_exact_at(&mut buf, offset)?;
+    Ok(buf)
+}
+
+/// Reads `len` bytes of the file at the given offset, without moving the
+/// cursor of the file, so concurrent readers share the handle.
+#[cfg(windows)]
+fn read_at(file: &File, offset: u64, len: usize) -> io::Result<Vec<u8>> {
+    use std::os::windows::fs::FileExt;
+    let mut buf = vec![0; len];
+    let mut read = 0;
+    while read < len {
+        match file.seek_read(&mut buf[read..], offset + read as u64)? {
+            0 => return Err(io::ErrorKind::UnexpectedEof.into()),
+            n => read += n,
+        }
+    }
+    Ok(buf)
+}
+
+fn damaged(id: u64) -> crate::KvsError {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("damaged table file {}", id),
+    )
+    .into()
+}
+
+pub(super) fn table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst", id))
+}
+
+fn tmp_table_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.sst.tmp", id))
+}
@@ -0,0 +1,101 @@
+//! Write-ahead logs of the `LsmEngine` memtables.
+//!
+//! Every write is stored as a checksummed frame, see `engines::frame`, whose
+//! payload is the encoded entries of the write, so a write batch is replayed
+//! entirely or not at all.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::entry::{decode_entry, encode_entry, Entry};
+use super::memtable::Memtable;
+use crate::engines::frame::{self, encode_frame, Frame, BATCH_FLAG};
+use crate::Result;
+
+/// The log of the writes to the current memtable.
+pub(super) struct Wal {
+    id: u64,
+    writer: BufWriter<File>,
+}
+
+impl Wal {
+    /// Creates the log file with the given id.
+    pub(super) fn create(dir: &Path, id: u64) -> Result<Wal> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(wal_path(dir, id))?;
+        Ok(Wal {
+            id,
+            writer: BufWriter::new(file),
+        })
+    }
+
+    /// Returns the id of the log file.
+    pub(super) fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Appends the entries of a write as a single frame, passing it on to
+    /// the operating system.
+    pub(super) fn append(&mut self, entries: &[(Vec<u8>, Entry)]) -> Result<()> {
+        let mut payload = Vec::new();
+        for (key, entry) in entries {
+            encode_entry(&mut payload, key, entry);
+        }
+        if payload.len() >= BATCH_FLAG as usize {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "write is too large").into());
+        }
+        self.writer.write_all(&encode_frame(&payload, 0))?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    /// Forces the log to the disk.
+    pub(super) fn sync(&mut self) -> Result<()> {
+        self.writer.flush()?;
+        self.writer.get_ref().sync_data()?;
+        Ok(())
+    }
+}
+
+/// Replays the log file with the given id into the memtable.
+///
+/// A torn write at the end of the file is cut off, see `frame::replay`.
+///
+/// # Errors
+///
+/// It returns `KvsError::Corruption` if a frame in the middle of the file is damaged.
+pub(super) fn replay(dir: &Path, id: u64, memtable: &Memtable) -> Result<()> {
+    frame::replay(&wal_path(dir, id), id, |frame, _| {
+        let entries = match frame {
+            Frame::Valid(payload) => decode_entries(&payload),
+            _ => None,
+        };
+        match entries {
+            Some(entries) => {
+                for (key, entry) in entries {
+                    memtable.insert(key, entry);
+                }
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Decodes the entries of a frame payload, or returns `None` if any of them
+/// is damaged.
+fn decode_entries(payload: &[u8]) -> Option<Vec<(Vec<u8>, Entry)>> {
+    let mut entries = Vec::new();
+    let mut rest = payload;
+    while !rest.is_empty() {
+        entries.push(decode_entry(&mut rest)?);
+    }
+    Some(entries)
+}
+
+pub(super) fn wal_path(dir: &Path, id: u64) -> PathBuf {
+    dir.join(format!("{}.wal", id))
+}
@@ -0,0 +1,338 @@
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::ops::RangeBounds;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+
+use crossbeam_skiplist::SkipMap;
+use serde::{Deserialize, Serialize};
+use serde_json::Deserializer;
+
+use super::{
+    check_removes, expiry_time, now_millis, time_left, BatchOp, KvEngine, Scan, Watcher, Watchers,
+    WriteBatch,
+};
+use crate::{KvsError, Result};
+
+/// A stored value along with its expiry time.
+struct Stored {
+    value: Vec<u8>,
+    // milliseconds since the unix epoch
+    expires_at: Option<u64>,
+}
+
+impl Stored {
+    /// Returns the value unless it has expired.
+    fn live_value(&self) -> Option<Vec<u8>> {
+        match self.expires_at {
+            Some(expires_at) if expires_at <= now_millis() => None,
+            _ => Some(self.value.clone()),
+        }
+    }
+}
+
+/// A key/value pair of a snapshot file.
+#[derive(Serialize, Deserialize)]
+struct SnapshotEntry {
+    #[serde(with = "crate::bytes")]
+    key: Vec<u8>,
+    #[serde(with = "crate::bytes")]
+    value: Vec<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    expires_at: Option<u64>,
+}
+
+struct Shared {
+    // the value of a key is replaced in place, so it never disappears
+    // from the map while it is overwritten
+    map: SkipMap<Vec<u8>, RwLock<Stored>>,
+    // serializes the writes, so the checks of a write are atomic with it
+    writer: Mutex<()>,
+    watchers: Watchers,
+    snapshot_path: Option<PathBuf>,
+}
+
+impl Drop for Shared {
+    fn drop(&mut self) {
+        if let Some(path) = &self.snapshot_path {
+            // there is no one to report the error to anymore.
+            let _ = save_snapshot(&self.map, path);
+        }
+    }
+}
+
+/// A `KvEngine` keeping the key/value pairs in memory only.
+///
+/// The pairs live in a lock-free skip list, so reads never wait for writes,
+/// while the writes are serialized. Expired keys are hidden from reads and
+/// stay in memory until they are written again.
+///
+/// The pairs are lost once the last handle is dropped, unless the engine is
+/// created with a snapshot file: the file is loaded at start, and written by
+/// `KvEngine::flush` and once the last handle is dropped.
+///
+/// ```rust
+/// # use kvs::{KvEngine, MemoryEngine, Result};
+/// # fn try_main() -> Result<()> {
+/// let engine = MemoryEngine::new();
+/// engine.set("key".to_owned(), "value".to_owned())?;
+/// assert_eq!(engine.get("key".to_owned())?, Some("value".to_owned()));
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Clone)]
+pub struct MemoryEngine {
+    shared: Arc<Shared>,
+}
+
+impl MemoryEngine {
+    /// Creates an empty engine.
+    pub fn new() -> MemoryEngine {
+        MemoryEngine::with(SkipMap::new(), None)
+    }
+
+    /// Creates an engine loaded from the snapshot file, if it exists, and
+    /// saving its pairs to the file on `KvEngine::flush` and when dropped.
+    ///
+    /// # Errors
+    ///
+    /// It propagates I/O or deserialization errors during loading the file.
+    pub fn with_snapshot(path: impl Into<PathBuf>) -> Result<MemoryEngine> {
+        let path = path.into();
+        let map = SkipMap::new();
+        match File::open(&path) {
+            Ok(file) => {
+                let now = now_millis();
+                for entry in Deserializer::from_reader(BufReader::new(file)).into_iter() {
+                    let SnapshotEntry {
+                        key,
+                        value,
+                        expires_at,
+                    } = entry?;
+                    if expires_at.map_or(true, |expires_at| expires_at > now) {
+                        map.insert(key, RwLock::new(Stored { value, expires_at }));
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e.into()),
+        }
+        Ok(MemoryEngine::with(map, Some(path)))
+    }
+
+    fn with(map: SkipMap<Vec<u8>, RwLock<Stored>>, snapshot_path: Option<PathBuf>) -> MemoryEngine {
+        MemoryEngine {
+            shared: Arc::new(Shared {
+                map,
+                writer: Mutex::new(()),
+                watchers: Watchers::default(),
+                snapshot_path,
+            }),
+        }
+    }
+
+    /// Returns the value of a key unless it is missing or expired.
+    fn live_value(&self, key: &[u8]) -> Option<Vec<u8>> {
+        let entry = self.shared.map.get(key)?;
+        let stored = entry.value().read().unwrap();
+        stored.live_value()
+    }
+
+    /// Applies the writes, notifying the watchers.
+    ///
+    /// Must be called under the writer lock.
+    fn apply(&self, ops: Vec<(BatchOp, Option<u64>)>) {
+        let events = self
+            .shared
+            .watchers
+            .events(ops.iter().map(|(op, _)| match op {
+                BatchOp::Set { key, value } => (key.as_slice(), Some(value.as_slice())),
+                BatchOp::Remove { key } => (key.as_slice(), None),
+            }));
+        for (op, expires_at) in ops {
+            match op {
+                BatchOp::Set { key, value } => {
+                    let stored = Stored { value, expires_at };
+                    match self.shared.map.get(&key) {
+                        Some(entry) => *entry.value().write().unwrap() = stored,
+                        None => {
+                            self.shared.map.insert(key, RwLock::new(stored));
+                        }
+                    }
+                }
+                BatchOp::Remove { key } => {
+                    self.shared.map.remove(&key);
+                }
+            }
+        }
+        self.shared.watchers.notify(events);
+    }
+}
+
+impl Default for MemoryEngine {
+    fn default() -> MemoryEngine {
+        MemoryEngine::new()
+    }
+}
+
+impl KvEngine for MemoryEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let _writer = self.shared.writer.lock().unwrap();
+        self.apply(vec![(BatchOp::Set { key, value }, None)]);
+        Ok(())
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_time(ttl);
+        let _writer = self.shared.writer.lock().unwrap();
+        self.apply(vec![(BatchOp::Set { key, value }, Some(expires_at))]);
+        Ok(())
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        Ok(self.live_value(&key))
+    }
+
+    fn get_bytes_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        let now = now_millis();
+        Ok(self.shared.map.get(&key).and_then(|entry| {
+            let stored = entry.value().read().unwrap();
+            match stored.expires_at {
+                Some(expires_at) if expires_at <= now => None,
+                expires_at => Some((
+                    stored.value.clone(),
+                    expires_at.map(|at| time_left(at, now)),
+                )),
+            }
+        }))
+    }
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// The pairs are read from the skip list as the iterator advances.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>> {
+        let range = (range.start_bound().cloned(), range.end_bound().cloned());
+        Ok(Box::new(self.shared.map.range(range).filter_map(|entry| {
+            let value = entry.value().read().unwrap().live_value()?;
+            Some(Ok((entry.key().clone(), value)))
+        })))
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        let _writer = self.shared.writer.lock().unwrap();
+        if self.live_value(&key).is_none() {
+            return Err(KvsError::KeyNotFound);
+        }
+        self.apply(vec![(BatchOp::Remove { key }, None)]);
+        Ok(())
+    }
+
+    /// Sets or removes a key if its current value is the expected one.
+    ///
+    /// The check and the write happen under the writer lock, so no other
+    /// write can come in between.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let _writer = self.shared.writer.lock().unwrap();
+        let current = self.live_value(&key);
+        if current != expected {
+            return Ok(false);
+        }
+        let op = match (current, new) {
+            (_, Some(value)) => BatchOp::Set { key, value },
+            (Some(_), None) => BatchOp::Remove { key },
+            (None, None) => return Ok(true),
+        };
+        self.apply(vec![(op, None)]);
+        Ok(true)
+    }
+
+    /// Applies all the writes of the batch atomically.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        let _writer = self.shared.writer.lock().unwrap();
+        check_removes(&batch, |key| Ok(self.live_value(key).is_some()))?;
+        self.apply(batch.into_iter().map(|op| (op, None)).collect());
+        Ok(())
+    }
+
+    /// Applies the batch if the read keys still hold the values they were
+    /// read with, checked under the writer lock.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn transact(&self, reads: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch) -> Result<()> {
+        let _writer = self.shared.writer.lock().unwrap();
+        if reads
+            .iter()
+            .any(|(key, value)| self.live_value(key) != *value)
+        {
+            return Err(KvsError::TxnConflict);
+        }
+        check_removes(&batch, |key| Ok(self.live_value(key).is_some()))?;
+        self.apply(batch.into_iter().map(|op| (op, None)).collect());
+        Ok(())
+    }
+
+    /// Watches the writes to the keys starting with the prefix.
+    ///
+    /// The events are sent under the writer lock, so they come in the order
+    /// of the writes.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        Ok(self.shared.watchers.subscribe(prefix))
+    }
+
+    /// Saves the pairs to the snapshot file, if the engine has one.
+    fn flush(&self) -> Result<()> {
+        match &self.shared.snapshot_path {
+            Some(path) => {
+                let _writer = self.shared.writer.lock().unwrap();
+                save_snapshot(&self.shared.map, path)
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes the live pairs to the snapshot file.
+///
+/// The file is written under a temporary name and renamed once it is
+/// complete, so a crash never leaves a truncated snapshot behind.
+fn save_snapshot(map: &SkipMap<Vec<u8>, RwLock<Stored>>, path: &Path) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    for entry in map.iter() {
+        let stored = entry.value().read().unwrap();
+        if let Some(value) = stored.live_value() {
+            let entry = SnapshotEntry {
+                key: entry.key().clone(),
+                value,
+                expires_at: stored.expires_at,
+            };
+            serde_json::to_writer(&mut writer, &entry)?;
+        }
+    }
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    fs::rename(tmp_path, path)?;
+    Ok(())
+}
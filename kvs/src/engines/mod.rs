@@ -0,0 +1,226 @@
+//! This module provides various key value storage engines.
+
+use std::ops::{Bound, RangeBounds};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::Result;
+
+pub(crate) use self::batch::check_removes;
+pub(crate) use self::lock::DirLock;
+pub(crate) use self::watch::Watchers;
+
+/// Iterator over the key/value pairs of a scan, in the order of the keys.
+pub type Scan<'a> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>)>> + 'a>;
+
+/// Trait for a key value storage engine.
+///
+/// Keys and values are arbitrary byte strings. The string methods are
+/// shorthands for the byte methods with UTF-8 encoded strings.
+///
+/// Engines are cheap to clone: the clones are handles to the same store,
+/// which can be moved to other threads and used concurrently.
+pub trait KvEngine: Clone + Send + 'static {
+    /// Sets the value of a string key to a string.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set(&self, key: String, value: String) -> Result<()> {
+        self.set_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a string key to a string which expires after `ttl`.
+    fn set_with_ttl(&self, key: String, value: String, ttl: Duration) -> Result<()> {
+        self.set_bytes_with_ttl(key.into_bytes(), value.into_bytes(), ttl)
+    }
+
+    /// Gets the string value of a given string key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::Utf8` if the value is not valid UTF-8.
+    fn get(&self, key: String) -> Result<Option<String>> {
+        match self.get_bytes(key.into_bytes())? {
+            Some(value) => Ok(Some(String::from_utf8(value)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove(&self, key: String) -> Result<()> {
+        self.remove_bytes(key.into_bytes())
+    }
+
+    /// Atomically replaces the value of a string key if it equals `expected`.
+    ///
+    /// `None` stands for a missing key: `expected` of `None` requires the key
+    /// to be absent, and `new` of `None` removes the key.
+    ///
+    /// Returns `true` if the value was replaced.
+    fn compare_and_swap(
+        &self,
+        key: String,
+        expected: Option<String>,
+        new: Option<String>,
+    ) -> Result<bool> {
+        self.compare_and_swap_bytes(
+            key.into_bytes(),
+            expected.map(String::into_bytes),
+            new.map(String::into_bytes),
+        )
+    }
+
+    /// Sets the value of a string key to a string unless the key exists.
+    ///
+    /// Returns `true` if the value was set.
+    fn set_if_absent(&self, key: String, value: String) -> Result<bool> {
+        self.set_if_absent_bytes(key.into_bytes(), value.into_bytes())
+    }
+
+    /// Sets the value of a key.
+    ///
+    /// If the key already exists, the previous value will be overwritten.
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    /// Sets the value of a key which expires after `ttl`.
+    ///
+    /// An expired key behaves as if it was removed. Setting the key again
+    /// replaces the TTL along with the value.
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()>;
+
+    /// Gets the value of a given key.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>>;
+
+    /// Gets the value of a given key along with the time left until it
+    /// expires, which is `None` for a key set without a TTL.
+    ///
+    /// Returns `None` if the given key does not exist.
+    fn get_bytes_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>>;
+
+    /// Removes a given key.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()>;
+
+    /// Atomically replaces the value of a key if it equals `expected`.
+    ///
+    /// `None` stands for a missing key: `expected` of `None` requires the key
+    /// to be absent, and `new` of `None` removes the key.
+    ///
+    /// Returns `true` if the value was replaced.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Sets the value of a key unless the key exists.
+    ///
+    /// Returns `true` if the value was set.
+    fn set_if_absent_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<bool> {
+        self.compare_and_swap_bytes(key, None, Some(value))
+    }
+
+    /// Applies all the writes of the batch atomically.
+    ///
+    /// After a crash either every write of the batch is visible or none.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case none of the writes is applied.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()>;
+
+    /// Applies all the writes of the batch atomically if every key of `reads`
+    /// still holds the value it was read with, `None` standing for a missing key.
+    ///
+    /// This commits an optimistic transaction whose reads happened elsewhere,
+    /// e.g. in a `KvsClient`.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed,
+    /// in which case none of the writes is applied.
+    fn transact(&self, reads: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch) -> Result<()>;
+
+    /// Watches the writes to the keys starting with the prefix.
+    ///
+    /// The returned `Watcher` receives an event for every set and remove of
+    /// such a key made after this call, in the order of the writes. Keys which
+    /// expire are not reported.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watcher>;
+
+    /// Makes sure all the writes done so far are persisted to the disk.
+    fn flush(&self) -> Result<()>;
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// Keys are compared as byte strings. The values are read lazily as the
+    /// iterator advances, so a key removed in the meantime is skipped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the start of the range is greater than its end.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>>;
+
+    /// Iterates over the keys starting with the prefix, in sorted order.
+    fn scan_prefix(&self, prefix: Vec<u8>) -> Result<Scan<'_>> {
+        self.scan(prefix_range(prefix))
+    }
+}
+
+/// Returns the range of the keys starting with the prefix.
+pub(crate) fn prefix_range(prefix: Vec<u8>) -> (Bound<Vec<u8>>, Bound<Vec<u8>>) {
+    // the first key after the prefix range: the prefix without its trailing
+    // 0xff bytes and with the last remaining byte incremented.
+    let mut end = prefix.clone();
+    while let Some(last) = end.pop() {
+        if last < u8::MAX {
+            end.push(last + 1);
+            return (Bound::Included(prefix), Bound::Excluded(end));
+        }
+    }
+    (Bound::Included(prefix), Bound::Unbounded)
+}
+
+/// Returns the current time in milliseconds since the unix epoch.
+pub(crate) fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |elapsed| elapsed.as_millis() as u64)
+}
+
+/// Returns the expiry time of an entry written now with the given TTL.
+pub(crate) fn expiry_time(ttl: Duration) -> u64 {
+    now_millis().saturating_add(ttl.as_millis() as u64)
+}
+
+/// Returns the time left until the expiry time, as of `now`.
+pub(crate) fn time_left(expires_at: u64, now: u64) -> Duration {
+    Duration::from_millis(expires_at.saturating_sub(now))
+}
+
+mod batch;
+mod frame;
+mod kvs;
+mod lock;
+mod lsm;
+mod memory;
+mod sled;
+mod watch;
+
+pub use self::batch::{BatchOp, WriteBatch};
+pub use self::kvs::{Codec, KeyVersion, KvStore, KvStoreOptions, Snapshot, Sync, Transaction};
+pub use self::lsm::{LsmEngine, LsmOptions};
+pub use self::memory::MemoryEngine;
+pub use self::sled::SledKvsEngine;
+pub use self::watch::{WatchEvent, Watcher};
@@ -0,0 +1,349 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::io;
+use std::ops::RangeBounds;
+use std::path::PathBuf;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use ::sled::transaction::{
+    abort, ConflictableTransactionResult, TransactionError, TransactionalTree,
+};
+use ::sled::{Batch, Db, Event, IVec};
+
+use super::{
+    check_removes, expiry_time, now_millis, time_left, BatchOp, DirLock, KvEngine, Scan,
+    WatchEvent, Watcher, WriteBatch,
+};
+use crate::{KvsError, Result};
+
+/// How often a watch thread checks whether its watcher is still there.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Tag of a stored value without an expiry time.
+const PLAIN_TAG: u8 = 0;
+/// Tag of a stored value followed by its expiry time.
+const EXPIRY_TAG: u8 = 1;
+
+/// Wrapper of `sled::Db` as a `KvEngine`.
+///
+/// Every value is stored behind a tag byte, followed by the expiry time of
+/// keys set with a TTL. Expired keys are hidden from reads and stay in the
+/// tree until they are written again.
+///
+/// Every write is flushed to the disk before it returns.
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: Db,
+    _lock: Arc<DirLock>,
+}
+
+impl SledKvsEngine {
+    /// Opens the sled database in the given directory.
+    ///
+    /// This will create a new directory if the given one does not exist.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::DirectoryLocked` if the directory is already
+    /// opened, by this or another process.
+    ///
+    /// It propagates sled errors.
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let path = path.into();
+        std::fs::create_dir_all(&path)?;
+        let lock = DirLock::acquire(&path)?;
+        Ok(SledKvsEngine {
+            db: ::sled::open(&path)?,
+            _lock: Arc::new(lock),
+        })
+    }
+
+    /// Stores the value, flushing it to the disk.
+    fn insert(&self, key: Vec<u8>, value: Vec<u8>, expires_at: Option<u64>) -> Result<()> {
+        self.db.insert(key, encode(&value, expires_at))?;
+        self.db.flush()?;
+        Ok(())
+    }
+
+    /// Runs `f` in a sled transaction flushed on commit.
+    ///
+    /// `f` may run several times if it conflicts with concurrent writes.
+    fn transaction<F>(&self, f: F) -> Result<()>
+    where
+        F: Fn(&TransactionalTree) -> ConflictableTransactionResult<(), KvsError>,
+    {
+        let result = self.db.transaction(|tx| {
+            f(tx)?;
+            tx.flush();
+            Ok(())
+        });
+        match result {
+            Ok(()) => Ok(()),
+            Err(TransactionError::Abort(e)) => Err(e),
+            Err(TransactionError::Storage(e)) => Err(e.into()),
+        }
+    }
+}
+
+impl KvEngine for SledKvsEngine {
+    fn set_bytes(&self, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert(key, value, None)
+    }
+
+    fn set_bytes_with_ttl(&self, key: Vec<u8>, value: Vec<u8>, ttl: Duration) -> Result<()> {
+        let expires_at = expiry_time(ttl);
+        self.insert(key, value, Some(expires_at))
+    }
+
+    fn get_bytes(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        live_value(self.db.get(key)?)
+    }
+
+    fn get_bytes_with_ttl(&self, key: Vec<u8>) -> Result<Option<(Vec<u8>, Option<Duration>)>> {
+        let raw = match self.db.get(key)? {
+            Some(raw) => raw,
+            None => return Ok(None),
+        };
+        let (value, expires_at) = decode(&raw)?;
+        let now = now_millis();
+        match expires_at {
+            Some(expires_at) if expires_at <= now => Ok(None),
+            expires_at => Ok(Some((
+                value.to_vec(),
+                expires_at.map(|at| time_left(at, now)),
+            ))),
+        }
+    }
+
+    /// Iterates over the keys within the range, in sorted order.
+    ///
+    /// The pairs are read from the sled tree as the iterator advances.
+    fn scan<R: RangeBounds<Vec<u8>>>(&self, range: R) -> Result<Scan<'_>> {
+        Ok(Box::new(self.db.range(range).filter_map(|pair| {
+            let (key, raw) = match pair {
+                Ok(pair) => pair,
+                Err(e) => return Some(Err(e.into())),
+            };
+            match live_value(Some(raw)) {
+                Ok(Some(value)) => Some(Ok((key.to_vec(), value))),
+                Ok(None) => None,
+                Err(e) => Some(Err(e)),
+            }
+        })))
+    }
+
+    /// Removes a given key.
+    ///
+    /// The entry is removed with the native compare-and-swap of sled, so an
+    /// expired entry is left alone. It is retried if the key changes between
+    /// the read and the swap.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the given key is not found.
+    fn remove_bytes(&self, key: Vec<u8>) -> Result<()> {
+        loop {
+            let raw = self.db.get(&key)?;
+            if live_value(raw.clone())?.is_none() {
+                return Err(KvsError::KeyNotFound);
+            }
+            if self
+                .db
+                .compare_and_swap(&key, raw, None as Option<&[u8]>)?
+                .is_ok()
+            {
+                self.db.flush()?;
+                return Ok(());
+            }
+        }
+    }
+
+    /// Sets or removes a key if its current value is the expected one.
+    ///
+    /// The stored value is swapped with the native compare-and-swap of sled,
+    /// which is retried if the key changes between the read and the swap.
+    fn compare_and_swap_bytes(
+        &self,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        loop {
+            let raw = self.db.get(&key)?;
+            let current = live_value(raw.clone())?;
+            if current != expected {
+                return Ok(false);
+            }
+            if current.is_none() && new.is_none() {
+                return Ok(true);
+            }
+            let new_raw = new.as_ref().map(|value| encode(value, None));
+            if self.db.compare_and_swap(&key, raw, new_raw)?.is_ok() {
+                self.db.flush()?;
+                return Ok(true);
+            }
+        }
+    }
+
+    /// Applies all the writes of the batch atomically as a `sled::Batch`.
+    ///
+    /// A batch removing keys is applied in a sled transaction, which checks
+    /// that the removed keys exist atomically with the writes.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn write_batch(&self, batch: WriteBatch) -> Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let sled_batch = to_sled_batch(&batch);
+        if batch.iter().all(|op| matches!(op, BatchOp::Set { .. })) {
+            self.db.apply_batch(sled_batch)?;
+            self.db.flush()?;
+            return Ok(());
+        }
+        self.transaction(|tx| apply_batch(tx, &batch, &sled_batch))
+    }
+
+    /// Applies the batch if the read keys still hold the values they were
+    /// read with, checking both in a single sled transaction.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::TxnConflict` if any of the read keys has changed.
+    ///
+    /// It returns `KvsError::KeyNotFound` if the batch removes a key which
+    /// does not exist, in which case nothing is written.
+    fn transact(&self, reads: Vec<(Vec<u8>, Option<Vec<u8>>)>, batch: WriteBatch) -> Result<()> {
+        let sled_batch = to_sled_batch(&batch);
+        self.transaction(|tx| {
+            for (key, value) in &reads {
+                if transactional(live_value(tx.get(key)?))? != *value {
+                    return abort(KvsError::TxnConflict);
+                }
+            }
+            apply_batch(tx, &batch, &sled_batch)
+        })
+    }
+
+    /// Watches the writes to the keys starting with the prefix.
+    ///
+    /// The events of a sled subscriber are forwarded by a thread, which ends
+    /// with the database or shortly after the watcher is dropped.
+    fn watch(&self, prefix: Vec<u8>) -> Result<Watcher> {
+        let mut subscriber = self.db.watch_prefix(prefix);
+        let (sender, watcher) = Watcher::channel();
+        thread::spawn(move || loop {
+            let event = match subscriber.next_timeout(WATCH_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if !sender.is_closed() => continue,
+                Err(_) => break,
+            };
+            let event = match event {
+                Event::Insert { key, value } => match decode(&value) {
+                    Ok((value, _)) => WatchEvent::Set {
+                        key: key.to_vec(),
+                        value: value.to_vec(),
+                    },
+                    Err(_) => continue,
+                },
+                Event::Remove { key } => WatchEvent::Remove { key: key.to_vec() },
+            };
+            if !sender.send(event) {
+                break;
+            }
+        });
+        Ok(watcher)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+}
+
+/// Converts the writes of the batch to a `sled::Batch`.
+fn to_sled_batch(batch: &WriteBatch) -> Batch {
+    let mut sled_batch = Batch::default();
+    for op in batch {
+        match op {
+            BatchOp::Set { key, value } => sled_batch.insert(key.as_slice(), encode(value, None)),
+            BatchOp::Remove { key } => sled_batch.remove(key.as_slice()),
+        }
+    }
+    sled_batch
+}
+
+/// Applies the `sled_batch` made of `batch` in the transaction, aborting it
+/// if the batch removes a missing key.
+fn apply_batch(
+    tx: &TransactionalTree,
+    batch: &WriteBatch,
+    sled_batch: &Batch,
+) -> ConflictableTransactionResult<(), KvsError> {
+    let mut live = HashMap::new();
+    for op in batch {
+        if let BatchOp::Remove { key } = op {
+            let found = transactional(live_value(tx.get(key.as_slice())?))?.is_some();
+            live.insert(key.as_slice(), found);
+        }
+    }
+    transactional(check_removes(batch, |key| Ok(live[key])))?;
+    tx.apply_batch(sled_batch)?;
+    Ok(())
+}
+
+/// Aborts the transaction with the error, if any.
+fn transactional<T>(result: Result<T>) -> ConflictableTransactionResult<T, KvsError> {
+    match result {
+        Ok(value) => Ok(value),
+        Err(e) => abort(e),
+    }
+}
+
+/// Encodes a value along with its expiry time.
+fn encode(value: &[u8], expires_at: Option<u64>) -> Vec<u8> {
+    let mut raw = Vec::with_capacity(value.len() + 9);
+    match expires_at {
+        Some(expires_at) => {
+            raw.push(EXPIRY_TAG);
+            raw.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => raw.push(PLAIN_TAG),
+    }
+    raw.extend_from_slice(value);
+    raw
+}
+
+/// Decodes a stored value and its expiry time.
+fn decode(raw: &[u8]) -> Result<(&[u8], Option<u64>)> {
+    match raw.split_first() {
+        Some((&PLAIN_TAG, value)) => Ok((value, None)),
+        Some((&EXPIRY_TAG, rest)) if rest.len() >= 8 => {
+            let (expires_at, value) = rest.split_at(8);
+            Ok((
+                value,
+                Some(u64::from_le_bytes(expires_at.try_into().unwrap())),
+            ))
+        }
+        _ => Err(io::Error::new(io::ErrorKind::InvalidData, "invalid sled value").into()),
+    }
+}
+
+/// Returns the value of a stored entry unless it is missing or expired.
+fn live_value(raw: Option<IVec>) -> Result<Option<Vec<u8>>> {
+    let raw = match raw {
+        Some(raw) => raw,
+        None => return Ok(None),
+    };
+    let (value, expires_at) = decode(&raw)?;
+    if expires_at.is_some_and(|expires_at| expires_at <= now_millis()) {
+        return Ok(None);
+    }
+    Ok(Some(value.to_vec()))
+}
@@ -0,0 +1,174 @@
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// A write to a watched key, as streamed by `KvEngine::watch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum WatchEvent {
+    /// The value of a key was set.
+    Set {
+        /// The key.
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        /// The new value.
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+    },
+    /// A key was removed.
+    Remove {
+        /// The key.
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+}
+
+impl WatchEvent {
+    /// Returns the key written.
+    pub fn key(&self) -> &[u8] {
+        match self {
+            WatchEvent::Set { key, .. } | WatchEvent::Remove { key } => key,
+        }
+    }
+}
+
+/// Number of events queued for a watcher before it is disconnected.
+const WATCH_QUEUE_LEN: usize = 4096;
+
+/// The events of the keys starting with a prefix, in the order of the writes.
+///
+/// Iterating blocks until the next event. The iteration ends once the engine
+/// is gone, or once the watcher falls too far behind: a watcher which does
+/// not take its events is disconnected when its queue is full.
+pub struct Watcher {
+    receiver: Receiver<WatchEvent>,
+    // the senders hold a weak reference, to tell a dropped watcher
+    _alive: Arc<()>,
+}
+
+impl Watcher {
+    /// Creates a watcher along with the sender of its events.
+    pub(crate) fn channel() -> (WatchSender, Watcher) {
+        let (sender, receiver) = mpsc::sync_channel(WATCH_QUEUE_LEN);
+        let alive = Arc::new(());
+        let sender = WatchSender {
+            sender,
+            watcher: Arc::downgrade(&alive),
+        };
+        (
+            sender,
+            Watcher {
+                receiver,
+                _alive: alive,
+            },
+        )
+    }
+
+    /// Waits for the next event at most for `timeout`.
+    ///
+    /// Returns `None` if no event came in time or the engine is gone.
+    pub fn next_timeout(&mut self, timeout: Duration) -> Option<WatchEvent> {
+        self.receiver.recv_timeout(timeout).ok()
+    }
+
+    /// Waits for the next event at most for `timeout`, telling a timeout
+    /// from the engine being gone.
+    pub(crate) fn recv_timeout(
+        &mut self,
+        timeout: Duration,
+    ) -> std::result::Result<WatchEvent, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = WatchEvent;
+
+    fn next(&mut self) -> Option<WatchEvent> {
+        self.receiver.recv().ok()
+    }
+}
+
+/// The sending end of the events of a `Watcher`.
+pub(crate) struct WatchSender {
+    sender: SyncSender<WatchEvent>,
+    watcher: Weak<()>,
+}
+
+impl WatchSender {
+    /// Returns `true` if the watcher has been dropped.
+    pub(crate) fn is_closed(&self) -> bool {
+        self.watcher.strong_count() == 0
+    }
+
+    /// Queues the event for the watcher without blocking.
+    ///
+    /// Returns `false` if the watcher has been dropped or its queue is full,
+    /// in which case the sender should be dropped to disconnect the watcher.
+    pub(crate) fn send(&self, event: WatchEvent) -> bool {
+        self.sender.try_send(event).is_ok()
+    }
+}
+
+/// The watchers of an engine, fed by its write path.
+#[derive(Default)]
+pub(crate) struct Watchers {
+    subscribers: Mutex<Vec<(Vec<u8>, WatchSender)>>,
+}
+
+impl Watchers {
+    /// Registers a watcher of the keys starting with the prefix, forgetting
+    /// the watchers which have been dropped.
+    pub(crate) fn subscribe(&self, prefix: Vec<u8>) -> Watcher {
+        let (sender, watcher) = Watcher::channel();
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, sender)| !sender.is_closed());
+        subscribers.push((prefix, sender));
+        watcher
+    }
+
+    /// Returns `true` if a watcher is interested in the key, forgetting
+    /// the watchers which have been dropped.
+    pub(crate) fn is_watched(&self, key: &[u8]) -> bool {
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(_, sender)| !sender.is_closed());
+        subscribers
+            .iter()
+            .any(|(prefix, _)| key.starts_with(prefix))
+    }
+
+    /// Returns the events of the writes to the watched keys, a write of
+    /// `None` being a remove.
+    pub(crate) fn events<'a, I>(&self, writes: I) -> Vec<WatchEvent>
+    where
+        I: IntoIterator<Item = (&'a [u8], Option<&'a [u8]>)>,
+    {
+        writes
+            .into_iter()
+            .filter(|(key, _)| self.is_watched(key))
+            .map(|(key, value)| match value {
+                Some(value) => WatchEvent::Set {
+                    key: key.to_vec(),
+                    value: value.to_vec(),
+                },
+                None => WatchEvent::Remove { key: key.to_vec() },
+            })
+            .collect()
+    }
+
+    /// Sends the events to the watchers interested in them and forgets
+    /// the watchers which have been dropped or fell too far behind.
+    pub(crate) fn notify(&self, events: Vec<WatchEvent>) {
+        if events.is_empty() {
+            return;
+        }
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.retain(|(prefix, sender)| {
+            events
+                .iter()
+                .filter(|event| event.key().starts_with(prefix))
+                .all(|event| sender.send(event.clone()))
+        });
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use thiserror::Error;
+
+/// Error type for kvs.
+#[derive(Error, Debug)]
+pub enum KvsError {
+    /// IO error.
+    #[error("{0}")]
+    Io(#[from] io::Error),
+    /// Serialization or deserialization error.
+    #[error("{0}")]
+    Serde(#[from] serde_json::Error),
+    /// BSON serialization error.
+    #[error("{0}")]
+    BsonSerialization(#[from] bson::ser::Error),
+    /// BSON deserialization error.
+    #[error("{0}")]
+    BsonDeserialization(#[from] bson::de::Error),
+    /// Sled error.
+    #[error("{0}")]
+    Sled(#[from] sled::Error),
+    /// Unknown codec name in the header of a data directory.
+    #[error("Unknown codec: {0}")]
+    UnknownCodec(String),
+    /// Unknown engine name in the marker of a data directory.
+    #[error("Unknown engine: {0}")]
+    UnknownEngine(String),
+    /// The data directory belongs to another engine than the requested one.
+    #[error("Data directory belongs to the {found} engine, not {requested}")]
+    EngineMismatch {
+        /// The engine asked for.
+        requested: String,
+        /// The engine recorded in the data directory.
+        found: String,
+    },
+    /// The data directory was written in a newer format.
+    #[error("Unsupported data format version: {0}")]
+    UnsupportedFormat(u32),
+    /// A value read as a string is not valid UTF-8.
+    #[error("{0}")]
+    Utf8(#[from] std::string::FromUtf8Error),
+    /// Removing non-existent key error.
+    #[error("Key not found")]
+    KeyNotFound,
+    /// A damaged log record.
+    #[error("Corrupted log record in generation {generation} at offset {offset}")]
+    Corruption {
+        /// Generation of the damaged log.
+        generation: u64,
+        /// Offset of the damaged record in the log.
+        offset: u64,
+    },
+    /// A key read by a transaction was changed by a concurrent write
+    /// before the transaction committed.
+    #[error("Transaction conflict")]
+    TxnConflict,
+    /// The data directory is used by another engine handle.
+    #[error("Data directory is locked by process {pid}")]
+    DirectoryLocked {
+        /// PID of the process holding the lock.
+        pid: u32,
+    },
+    /// Error with a string message, e.g. returned by the server.
+    #[error("{0}")]
+    StringError(String),
+    /// Unexpected command type error.
+    /// It indicated a corrupted log or a program bug.
+    #[error("Unexpected command type")]
+    UnexpectedCommandType,
+}
+
+/// Result type for kvs.
+pub type Result<T> = std::result::Result<T, KvsError>;
@@ -1 +1,20 @@
+#![deny(missing_docs)]
+//! A simple key/value store.
 
+pub use client::{KvsClient, RemoteTransaction, RemoteWatcher};
+pub use engines::{
+    BatchOp, Codec, KeyVersion, KvEngine, KvStore, KvStoreOptions, LsmEngine, LsmOptions,
+    MemoryEngine, Scan, SledKvsEngine, Snapshot, Sync, Transaction, WatchEvent, Watcher,
+    WriteBatch,
+};
+pub use error::{KvsError, Result};
+pub use marker::EngineMarker;
+pub use server::KvsServer;
+
+mod bytes;
+mod client;
+mod engines;
+mod error;
+mod marker;
+mod protocol;
+mod server;
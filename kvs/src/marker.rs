@@ -0,0 +1,90 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::{KvsError, Result};
+
+/// Name of the file recording the engine of a data directory.
+const MARKER_FILE: &str = "kvs.engine";
+
+/// The storage engine a data directory belongs to, recorded in its marker file.
+///
+/// A server refuses to open a directory with another engine than the one
+/// which wrote it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EngineMarker {
+    /// Name of the engine, as given to `kv-server --engine`.
+    pub engine: String,
+    /// Version of the on-disk format of the engine.
+    pub format_version: u32,
+}
+
+impl EngineMarker {
+    /// The on-disk format version written by this build.
+    pub const FORMAT_VERSION: u32 = 1;
+
+    /// Creates the marker of a directory written by the engine with this build.
+    pub fn new(engine: impl Into<String>) -> EngineMarker {
+        EngineMarker {
+            engine: engine.into(),
+            format_version: EngineMarker::FORMAT_VERSION,
+        }
+    }
+
+    /// Reads the marker of the data directory, if any.
+    ///
+    /// # Errors
+    ///
+    /// It returns `KvsError::UnsupportedFormat` if the directory was written
+    /// in a newer format than this build knows.
+    pub fn read(dir: &Path) -> Result<Option<EngineMarker>> {
+        let content = match fs::read_to_string(marker_path(dir)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut engine = None;
+        let mut format_version = None;
+        for line in content.lines() {
+            match line.split_once('=') {
+                Some(("engine", name)) => engine = Some(name.trim().to_owned()),
+                Some(("format", version)) => format_version = version.trim().parse().ok(),
+                _ => {}
+            }
+        }
+        let marker = match (engine, format_version) {
+            (Some(engine), Some(format_version)) => EngineMarker {
+                engine,
+                format_version,
+            },
+            _ => {
+                return Err(
+                    io::Error::new(io::ErrorKind::InvalidData, "invalid engine marker").into(),
+                )
+            }
+        };
+        if marker.format_version > EngineMarker::FORMAT_VERSION {
+            return Err(KvsError::UnsupportedFormat(marker.format_version));
+        }
+        Ok(Some(marker))
+    }
+
+    /// Records the marker in the data directory.
+    ///
+    /// The file is written under a temporary name and renamed, so the marker
+    /// is swapped atomically.
+    pub fn write(&self, dir: &Path) -> Result<()> {
+        let tmp_path = dir.join(format!("{}.tmp", MARKER_FILE));
+        fs::write(
+            &tmp_path,
+            format!("engine={}\nformat={}\n", self.engine, self.format_version),
+        )?;
+        fs::File::open(&tmp_path)?.sync_all()?;
+        fs::rename(tmp_path, marker_path(dir))?;
+        Ok(())
+    }
+}
+
+fn marker_path(dir: &Path) -> PathBuf {
+    dir.join(MARKER_FILE)
+}
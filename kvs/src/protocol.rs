@@ -0,0 +1,104 @@
+//! Messages exchanged between `KvsClient` and `KvsServer`.
+//!
+//! Every message is a json value on the TCP stream. Keys and values are byte
+//! strings, so binary data travels as-is.
+//!
+//! A watch request turns the connection into a stream of `WatchResponse`s
+//! which lasts until either side closes it.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{WatchEvent, WriteBatch};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum Request {
+    Get {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Set {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes")]
+        value: Vec<u8>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        ttl: Option<Duration>,
+    },
+    Remove {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+    },
+    Batch {
+        batch: WriteBatch,
+    },
+    CompareAndSwap {
+        #[serde(with = "crate::bytes")]
+        key: Vec<u8>,
+        #[serde(with = "crate::bytes::option")]
+        expected: Option<Vec<u8>>,
+        #[serde(with = "crate::bytes::option")]
+        new: Option<Vec<u8>>,
+    },
+    Transaction {
+        reads: Vec<TxnRead>,
+        batch: WriteBatch,
+    },
+    Watch {
+        #[serde(with = "crate::bytes")]
+        prefix: Vec<u8>,
+    },
+}
+
+/// A key read by a transaction along with the value it was read with.
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct TxnRead {
+    #[serde(with = "crate::bytes")]
+    pub(crate) key: Vec<u8>,
+    #[serde(with = "crate::bytes::option")]
+    pub(crate) value: Option<Vec<u8>>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum GetResponse {
+    Ok(#[serde(with = "crate::bytes::option")] Option<Vec<u8>>),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum SetResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum RemoveResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum BatchResponse {
+    Ok(()),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum CompareAndSwapResponse {
+    Ok(bool),
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum TransactionResponse {
+    Ok(()),
+    Conflict,
+    Err(String),
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) enum WatchResponse {
+    Event(WatchEvent),
+    Err(String),
+}
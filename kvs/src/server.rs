@@ -0,0 +1,157 @@
+use std::io::{self, BufReader, BufWriter, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::RecvTimeoutError;
+use std::thread;
+use std::time::Duration;
+
+use serde_json::Deserializer;
+use slog::{debug, error, Logger};
+
+use crate::protocol::{
+    BatchResponse, CompareAndSwapResponse, GetResponse, RemoveResponse, Request, SetResponse,
+    TransactionResponse, WatchResponse,
+};
+use crate::{KvEngine, KvsError, Result, Watcher};
+
+/// How often a watch connection without events is checked for being closed.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// The server of a key value store.
+///
+/// Every connection is served by a thread of its own through a clone of
+/// the engine, so clients are served concurrently.
+#[derive(Clone)]
+pub struct KvsServer<E: KvEngine> {
+    engine: E,
+    logger: Logger,
+}
+
+impl<E: KvEngine> KvsServer<E> {
+    /// Creates a `KvsServer` with the given storage engine.
+    pub fn new(engine: E, logger: Logger) -> Self {
+        KvsServer { engine, logger }
+    }
+
+    /// Runs the server listening on the given address.
+    pub fn run<A: ToSocketAddrs>(self, addr: A) -> Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let server = self.clone();
+                    thread::spawn(move || {
+                        if let Err(e) = server.serve(stream) {
+                            error!(server.logger, "Error on serving client"; "error" => %e);
+                        }
+                    });
+                }
+                Err(e) => error!(self.logger, "Connection failed"; "error" => %e),
+            }
+        }
+        Ok(())
+    }
+
+    fn serve(&self, tcp: TcpStream) -> Result<()> {
+        let peer_addr = tcp.peer_addr()?;
+        let reader = BufReader::new(&tcp);
+        let mut writer = BufWriter::new(&tcp);
+        let req_reader = Deserializer::from_reader(reader).into_iter::<Request>();
+
+        macro_rules! send_resp {
+            ($resp:expr) => {{
+                let resp = $resp;
+                serde_json::to_writer(&mut writer, &resp)?;
+                writer.flush()?;
+                debug!(self.logger, "Response sent"; "peer" => %peer_addr, "response" => ?resp);
+            }};
+        }
+
+        for req in req_reader {
+            let req = req?;
+            debug!(self.logger, "Receive request"; "peer" => %peer_addr, "request" => ?req);
+            match req {
+                Request::Get { key } => send_resp!(match self.engine.get_bytes(key) {
+                    Ok(value) => GetResponse::Ok(value),
+                    Err(e) => GetResponse::Err(e.to_string()),
+                }),
+                Request::Set { key, value, ttl } => {
+                    let res = match ttl {
+                        Some(ttl) => self.engine.set_bytes_with_ttl(key, value, ttl),
+                        None => self.engine.set_bytes(key, value),
+                    };
+                    send_resp!(match res {
+                        Ok(_) => SetResponse::Ok(()),
+                        Err(e) => SetResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Remove { key } => send_resp!(match self.engine.remove_bytes(key) {
+                    Ok(_) => RemoveResponse::Ok(()),
+                    Err(e) => RemoveResponse::Err(e.to_string()),
+                }),
+                Request::Batch { batch } => send_resp!(match self.engine.write_batch(batch) {
+                    Ok(_) => BatchResponse::Ok(()),
+                    Err(e) => BatchResponse::Err(e.to_string()),
+                }),
+                Request::CompareAndSwap { key, expected, new } => {
+                    send_resp!(
+                        match self.engine.compare_and_swap_bytes(key, expected, new) {
+                            Ok(swapped) => CompareAndSwapResponse::Ok(swapped),
+                            Err(e) => CompareAndSwapResponse::Err(e.to_string()),
+                        }
+                    )
+                }
+                Request::Transaction { reads, batch } => {
+                    let reads = reads
+                        .into_iter()
+                        .map(|read| (read.key, read.value))
+                        .collect();
+                    send_resp!(match self.engine.transact(reads, batch) {
+                        Ok(_) => TransactionResponse::Ok(()),
+                        Err(KvsError::TxnConflict) => TransactionResponse::Conflict,
+                        Err(e) => TransactionResponse::Err(e.to_string()),
+                    })
+                }
+                Request::Watch { prefix } => match self.engine.watch(prefix) {
+                    Ok(watcher) => {
+                        let res = stream_events(&tcp, watcher);
+                        debug!(self.logger, "Watch closed"; "peer" => %peer_addr, "result" => ?res);
+                        return Ok(());
+                    }
+                    Err(e) => send_resp!(WatchResponse::Err(e.to_string())),
+                },
+            };
+        }
+        Ok(())
+    }
+}
+
+/// Sends the events of the watcher to the client until it goes away.
+fn stream_events(tcp: &TcpStream, mut watcher: Watcher) -> Result<()> {
+    let mut writer = BufWriter::new(tcp);
+    loop {
+        match watcher.recv_timeout(WATCH_POLL_INTERVAL) {
+            Ok(event) => {
+                serde_json::to_writer(&mut writer, &WatchResponse::Event(event))?;
+                writer.flush()?;
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                if peer_closed(tcp)? {
+                    return Ok(());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => return Ok(()),
+        }
+    }
+}
+
+/// Returns `true` if the client has closed the connection.
+fn peer_closed(tcp: &TcpStream) -> io::Result<bool> {
+    tcp.set_nonblocking(true)?;
+    let res = tcp.peek(&mut [0; 1]);
+    tcp.set_nonblocking(false)?;
+    match res {
+        Ok(len) => Ok(len == 0),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
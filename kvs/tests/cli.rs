@@ -1,7 +1,15 @@
+// the baseline tests pass arguments as `&[...]` and leave killed servers
+// unwaited, both of which newer clippy flags.
+#![allow(clippy::needless_borrows_for_generic_args, clippy::zombie_processes)]
+
 use assert_cmd::prelude::*;
+use kvs::{
+    EngineMarker, KvEngine, KvStore, KvsClient, KvsError, LsmEngine, SledKvsEngine, WriteBatch,
+};
 use predicates::str::{contains, is_empty};
 use std::fs::{self, File};
-use std::process::Command;
+use std::io::{BufRead, BufReader};
+use std::process::{Command, Stdio};
 use std::sync::mpsc;
 use std::thread;
 use std::time::Duration;
@@ -336,3 +344,695 @@ fn cli_access_server_kvs_engine() {
 fn cli_access_server_sled_engine() {
     cli_access_server("sled", "127.0.0.1:4005");
 }
+
+#[test]
+fn cli_access_server_lsm_engine() {
+    cli_access_server("lsm", "127.0.0.1:4017");
+}
+
+fn cli_binary_values(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let key = vec![0xff, 0x00, b'k'];
+    let value = vec![0x80, 0xfe, 0x00, 0x01];
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set_bytes(key.clone(), value.clone()).unwrap();
+    client.set_bytes(b"key".to_vec(), value.clone()).unwrap();
+    assert_eq!(client.get_bytes(key.clone()).unwrap(), Some(value.clone()));
+    client.remove_bytes(key.clone()).unwrap();
+    assert_eq!(client.get_bytes(key).unwrap(), None);
+    drop(client);
+
+    let mut expected = value;
+    expected.push(b'\n');
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(expected);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_binary_values_kvs_engine() {
+    cli_binary_values("kvs", "127.0.0.1:4006");
+}
+
+#[test]
+fn cli_binary_values_sled_engine() {
+    cli_binary_values("sled", "127.0.0.1:4018");
+}
+
+#[test]
+fn cli_set_with_ttl() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args([
+            "set",
+            "key1",
+            "value1",
+            "--ttl",
+            "1",
+            "--addr",
+            "127.0.0.1:4007",
+        ])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(is_empty());
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    thread::sleep(Duration::from_millis(1500));
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["get", "key1", "--addr", "127.0.0.1:4007"])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+fn cli_write_batch(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").remove("key1");
+    client.write_batch(batch).unwrap();
+    assert_eq!(client.get("key1".to_owned()).unwrap(), None);
+    assert_eq!(
+        client.get("key2".to_owned()).unwrap(),
+        Some("value2".to_owned())
+    );
+
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").remove("key1");
+    assert!(client.write_batch(batch).is_err());
+    assert_eq!(client.get("key3".to_owned()).unwrap(), None);
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_write_batch_kvs_engine() {
+    cli_write_batch("kvs", "127.0.0.1:4008");
+}
+
+#[test]
+fn cli_write_batch_sled_engine() {
+    cli_write_batch("sled", "127.0.0.1:4019");
+}
+
+fn cli_compare_and_swap(engine: &str, addr: &str) {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", engine, "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let client = |args: &[&str]| {
+        let mut cmd = Command::cargo_bin("kv-client").unwrap();
+        cmd.args(args).args(["--addr", addr]).current_dir(&temp_dir);
+        cmd
+    };
+    client(&["setnx", "lock", "owner1"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["setnx", "lock", "owner2"])
+        .assert()
+        .failure()
+        .stderr(contains("Key already exists"));
+    client(&["cas", "lock", "--expected", "owner2", "--new", "owner3"])
+        .assert()
+        .failure()
+        .stderr(contains("Value mismatch"));
+    client(&["cas", "lock", "--expected", "owner1", "--new", "owner3"])
+        .assert()
+        .success()
+        .stdout(is_empty());
+    client(&["get", "lock"])
+        .assert()
+        .success()
+        .stdout("owner3\n");
+    client(&["cas", "lock", "--expected", "owner3"])
+        .assert()
+        .success();
+    client(&["get", "lock"])
+        .assert()
+        .success()
+        .stdout(contains("Key not found"));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+#[test]
+fn cli_compare_and_swap_kvs_engine() {
+    cli_compare_and_swap("kvs", "127.0.0.1:4009");
+}
+
+#[test]
+fn cli_compare_and_swap_sled_engine() {
+    cli_compare_and_swap("sled", "127.0.0.1:4020");
+}
+
+// Transactions should be committed through the protocol.
+#[test]
+fn cli_transaction() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4010";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("from".to_owned(), "10".to_owned()).unwrap();
+    let mut txn = client.transaction();
+    let from: u32 = txn
+        .get("from".to_owned())
+        .unwrap()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert_eq!(txn.get("to".to_owned()).unwrap(), None);
+    txn.set("from".to_owned(), (from - 1).to_string());
+    txn.set("to".to_owned(), "1".to_owned());
+    assert!(matches!(
+        txn.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    txn.commit().unwrap();
+    assert_eq!(client.get("from".to_owned()).unwrap(), Some("9".to_owned()));
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("1".to_owned()));
+
+    // a key set and removed by the transaction alone is not written at all.
+    let mut txn = client.transaction();
+    txn.set("temp".to_owned(), "value".to_owned());
+    txn.remove("temp".to_owned()).unwrap();
+    txn.commit().unwrap();
+    assert_eq!(client.get("temp".to_owned()).unwrap(), None);
+
+    // a transaction which read a stale value is rejected.
+    let reads = vec![(b"from".to_vec(), Some(b"10".to_vec()))];
+    let mut batch = WriteBatch::new();
+    batch.remove("to");
+    assert!(matches!(
+        client.transact(reads, batch),
+        Err(KvsError::TxnConflict)
+    ));
+    assert_eq!(client.get("to".to_owned()).unwrap(), Some("1".to_owned()));
+    drop(client);
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// `kv-client watch` should print the writes to the watched keys as they happen.
+#[test]
+fn cli_watch() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4011";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut watch = Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["watch", "user:", "--addr", addr])
+        .current_dir(&temp_dir)
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let stdout = watch.stdout.take().unwrap();
+    let (sender, receiver) = mpsc::sync_channel(0);
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines() {
+            sender.send(line.unwrap()).unwrap();
+        }
+    });
+    thread::sleep(Duration::from_secs(1));
+
+    // the watch doesn't keep the server from serving other clients.
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("user:1".to_owned(), "alice".to_owned()).unwrap();
+    client.set("other".to_owned(), "value".to_owned()).unwrap();
+    client.remove("user:1".to_owned()).unwrap();
+    drop(client);
+
+    let timeout = Duration::from_secs(5);
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), "set user:1 alice");
+    assert_eq!(receiver.recv_timeout(timeout).unwrap(), "rm user:1");
+
+    watch.kill().expect("watch exited before killed");
+    watch.wait().expect("failed to wait on watch");
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// The server should serve several connections at the same time.
+#[test]
+fn cli_concurrent_clients() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4012";
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", addr])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    let mut client1 = KvsClient::connect(addr).unwrap();
+    let mut client2 = KvsClient::connect(addr).unwrap();
+    client1.set("key".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client2.get("key".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    Command::cargo_bin("kv-client")
+        .unwrap()
+        .args(["get", "key", "--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .success()
+        .stdout("value1\n");
+
+    // a write through another connection makes the transaction fail.
+    let mut txn = client1.transaction();
+    assert_eq!(
+        txn.get("key".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    txn.set("key".to_owned(), "value2".to_owned());
+    client2.set("key".to_owned(), "value3".to_owned()).unwrap();
+    assert!(matches!(txn.commit(), Err(KvsError::TxnConflict)));
+    assert_eq!(
+        client1.get("key".to_owned()).unwrap(),
+        Some("value3".to_owned())
+    );
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            thread::spawn(move || {
+                let mut client = KvsClient::connect(addr).unwrap();
+                for iter in 0..50 {
+                    let key = format!("key{}_{}", thread_id, iter);
+                    client.set(key.clone(), iter.to_string()).unwrap();
+                    assert_eq!(client.get(key).unwrap(), Some(iter.to_string()));
+                }
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap();
+    }
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// A second server in the same directory should refuse to start.
+#[test]
+fn cli_directory_locked() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4013"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4014"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains(format!("locked by process {}", child.id())));
+
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+}
+
+// The server should record its engine in the data directory and keep using it.
+#[test]
+fn cli_engine_marker() {
+    let temp_dir = TempDir::new().unwrap();
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+    let marker = EngineMarker::read(temp_dir.path()).unwrap().unwrap();
+    assert_eq!(marker, EngineMarker::new("kvs"));
+
+    // the engine defaults to the one of the directory.
+    let mut server = Command::cargo_bin("kv-server").unwrap();
+    let mut child = server
+        .args(["--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    thread::sleep(Duration::from_secs(1));
+    assert!(child.try_wait().unwrap().is_none());
+    child.kill().expect("server exited before killed");
+    let output = child.wait_with_output().expect("failed to wait on server");
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Storage engine: kvs"));
+
+    EngineMarker {
+        engine: "kvs".to_owned(),
+        format_version: EngineMarker::FORMAT_VERSION + 1,
+    }
+    .write(temp_dir.path())
+    .unwrap();
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unsupported data format version"));
+
+    EngineMarker::new("unknown").write(temp_dir.path()).unwrap();
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--addr", "127.0.0.1:4015"])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("Unknown engine: unknown"));
+}
+
+// `kvs-admin convert` should refuse to convert a directory to its own engine.
+#[test]
+fn admin_convert_same_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    EngineMarker::new("kvs").write(temp_dir.path()).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "kvs"])
+        .arg(temp_dir.path())
+        .assert()
+        .failure()
+        .stderr(contains("already uses the kvs engine"));
+}
+
+// `kvs-admin convert` should copy every key into the other engine and
+// switch the engine of the directory.
+#[test]
+fn admin_convert() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    {
+        let engine = SledKvsEngine::open(&dir).unwrap();
+        for i in 0..2500 {
+            engine
+                .set(format!("key{}", i), format!("value{}", i))
+                .unwrap();
+        }
+        engine
+            .set_with_ttl(
+                "session".to_owned(),
+                "token".to_owned(),
+                Duration::from_secs(3600),
+            )
+            .unwrap();
+        EngineMarker::new("sled").write(&dir).unwrap();
+    }
+
+    // the directory holds sled data.
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "sled"])
+        .arg(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("belongs to the sled engine"));
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "sled", "--to", "kvs"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(contains("Converted 2501 keys from sled to kvs"));
+    assert_eq!(
+        EngineMarker::read(&dir).unwrap(),
+        Some(EngineMarker::new("kvs"))
+    );
+    assert!(temp_dir.path().join("data.sled").exists());
+    {
+        let store = KvStore::open(&dir).unwrap();
+        assert_eq!(store.scan(..).unwrap().count(), 2501);
+        assert_eq!(
+            store.get("key1234".to_owned()).unwrap(),
+            Some("value1234".to_owned())
+        );
+        let (value, ttl) = store
+            .get_bytes_with_ttl(b"session".to_vec())
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, b"token");
+        assert!(ttl.unwrap() > Duration::from_secs(3500));
+    }
+
+    // the old copy of the kvs data would be overwritten.
+    fs::create_dir(temp_dir.path().join("data.kvs")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "sled"])
+        .arg(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("is in the way of the old data"));
+    assert_eq!(
+        EngineMarker::read(&dir).unwrap(),
+        Some(EngineMarker::new("kvs"))
+    );
+    fs::remove_dir(temp_dir.path().join("data.kvs")).unwrap();
+
+    // and back, once the old copy is out of the way.
+    fs::remove_dir_all(temp_dir.path().join("data.sled")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "sled"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(contains("Converted 2501 keys from kvs to sled"));
+    let engine = SledKvsEngine::open(&dir).unwrap();
+    assert_eq!(
+        engine.get("key42".to_owned()).unwrap(),
+        Some("value42".to_owned())
+    );
+    drop(engine);
+
+    fs::remove_dir_all(temp_dir.path().join("data.kvs")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "sled", "--to", "lsm"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(contains("Converted 2501 keys from sled to lsm"));
+    let engine = LsmEngine::open(&dir).unwrap();
+    assert_eq!(engine.scan(..).unwrap().count(), 2501);
+    let (_, ttl) = engine
+        .get_bytes_with_ttl(b"session".to_vec())
+        .unwrap()
+        .unwrap();
+    assert!(ttl.is_some());
+    assert_eq!(
+        engine.get("key2499".to_owned()).unwrap(),
+        Some("value2499".to_owned())
+    );
+}
+
+// `kvs-admin convert` should finish a conversion interrupted between moving
+// the old directory away and moving the converted copy in its place.
+#[test]
+fn admin_convert_interrupted_swap() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    let old_dir = temp_dir.path().join("data.sled");
+    let new_dir = temp_dir.path().join("data.convert");
+    {
+        let engine = SledKvsEngine::open(&old_dir).unwrap();
+        engine.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        EngineMarker::new("sled").write(&old_dir).unwrap();
+        let store = KvStore::open(&new_dir).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+        EngineMarker::new("kvs").write(&new_dir).unwrap();
+    }
+
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "sled", "--to", "kvs"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(contains("Finished an interrupted conversion"));
+    assert!(!new_dir.exists());
+    assert_eq!(
+        EngineMarker::read(&dir).unwrap(),
+        Some(EngineMarker::new("kvs"))
+    );
+    let store = KvStore::open(&dir).unwrap();
+    assert_eq!(
+        store.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// `kvs-admin convert` should only trust `--from` for a directory without a
+// marker if its files are those of that engine.
+#[test]
+fn admin_convert_unmarked() {
+    let temp_dir = TempDir::new().unwrap();
+    let dir = temp_dir.path().join("data");
+    {
+        let store = KvStore::open(&dir).unwrap();
+        store.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    }
+    for from in &["sled", "lsm"] {
+        Command::cargo_bin("kvs-admin")
+            .unwrap()
+            .args(["convert", "--from", from, "--to", "kvs"])
+            .arg(&dir)
+            .assert()
+            .failure()
+            .stderr(contains("has no engine marker"));
+    }
+    fs::write(dir.join("notes.txt"), "foreign").unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "sled"])
+        .arg(&dir)
+        .assert()
+        .failure()
+        .stderr(contains("has no engine marker"));
+    assert_eq!(EngineMarker::read(&dir).unwrap(), None);
+
+    fs::remove_file(dir.join("notes.txt")).unwrap();
+    Command::cargo_bin("kvs-admin")
+        .unwrap()
+        .args(["convert", "--from", "kvs", "--to", "sled"])
+        .arg(&dir)
+        .assert()
+        .success()
+        .stdout(contains("Converted 1 keys from kvs to sled"));
+    let engine = SledKvsEngine::open(&dir).unwrap();
+    assert_eq!(
+        engine.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+}
+
+// The memory engine should leave the directory alone and keep its pairs in
+// the snapshot file across a graceful shutdown.
+#[test]
+fn cli_memory_engine() {
+    let temp_dir = TempDir::new().unwrap();
+    let addr = "127.0.0.1:4016";
+    let start = || {
+        let child = Command::cargo_bin("kv-server")
+            .unwrap()
+            .args(["--engine", "memory", "--snapshot", "snapshot.json"])
+            .args(["--addr", addr])
+            .current_dir(&temp_dir)
+            .spawn()
+            .unwrap();
+        thread::sleep(Duration::from_secs(1));
+        child
+    };
+
+    let mut child = start();
+    let mut client = KvsClient::connect(addr).unwrap();
+    client.set("key1".to_owned(), "value1".to_owned()).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    Command::new("kill")
+        .arg(child.id().to_string())
+        .assert()
+        .success();
+    assert!(child.wait().expect("failed to wait on server").success());
+    assert!(temp_dir.path().join("snapshot.json").exists());
+    assert_eq!(EngineMarker::read(temp_dir.path()).unwrap(), None);
+
+    let mut child = start();
+    let mut client = KvsClient::connect(addr).unwrap();
+    assert_eq!(
+        client.get("key1".to_owned()).unwrap(),
+        Some("value1".to_owned())
+    );
+    child.kill().expect("server exited before killed");
+    child.wait().expect("failed to wait on server");
+
+    Command::cargo_bin("kv-server")
+        .unwrap()
+        .args(["--engine", "kvs", "--snapshot", "snapshot.json"])
+        .args(["--addr", addr])
+        .current_dir(&temp_dir)
+        .assert()
+        .failure()
+        .stderr(contains("does not take a snapshot file"));
+}
@@ -0,0 +1,466 @@
+// The behavior every `KvEngine` shares, checked for the engines other than
+// `KvStore`, which has its own tests.
+
+use kvs::{
+    KvEngine, KvsError, LsmEngine, LsmOptions, MemoryEngine, Result, SledKvsEngine, WatchEvent,
+    WriteBatch,
+};
+use std::fs::{self, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::thread;
+use std::time::Duration;
+use tempfile::TempDir;
+
+fn check_basics(engine: &impl KvEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    engine.set("key1".to_owned(), "value3".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("missing".to_owned())?, None);
+
+    engine.remove("key1".to_owned())?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert!(matches!(
+        engine.remove("key1".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    engine.set_bytes(vec![0, 255], vec![1, 2, 3])?;
+    assert_eq!(engine.get_bytes(vec![0, 255])?, Some(vec![1, 2, 3]));
+    engine.remove_bytes(vec![0, 255])?;
+    Ok(())
+}
+
+fn check_scan(engine: &impl KvEngine) -> Result<()> {
+    for key in &["b", "a", "ab", "c", "b1"] {
+        engine.set(key.to_string(), key.to_uppercase())?;
+    }
+    let keys = |scan: kvs::Scan| -> Result<Vec<Vec<u8>>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(
+        keys(engine.scan(b"a".to_vec()..b"c".to_vec())?)?,
+        vec![b"a".to_vec(), b"ab".to_vec(), b"b".to_vec(), b"b1".to_vec()]
+    );
+    assert_eq!(
+        engine
+            .scan_prefix(b"b".to_vec())?
+            .collect::<Result<Vec<_>>>()?,
+        vec![
+            (b"b".to_vec(), b"B".to_vec()),
+            (b"b1".to_vec(), b"B1".to_vec())
+        ]
+    );
+    assert_eq!(
+        keys(engine.scan(b"b1".to_vec()..=b"c".to_vec())?)?,
+        vec![b"b1".to_vec(), b"c".to_vec()]
+    );
+
+    // a prefix of 0xff bytes has no upper bound.
+    engine.set_bytes(vec![0xff, 0xff], b"high".to_vec())?;
+    engine.set_bytes(vec![0xff, 0xff, 0x00], b"higher".to_vec())?;
+    assert_eq!(
+        keys(engine.scan_prefix(vec![0xff, 0xff])?)?,
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x00]]
+    );
+    engine.remove("ab".to_owned())?;
+    assert_eq!(keys(engine.scan(..)?)?.len(), 6);
+    Ok(())
+}
+
+fn check_ttl(engine: &impl KvEngine) -> Result<()> {
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    assert_eq!(engine.get("short".to_owned())?, Some("value".to_owned()));
+    thread::sleep(Duration::from_millis(200));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("value".to_owned()));
+    assert_eq!(engine.get_bytes_with_ttl(b"short".to_vec())?, None);
+    let (value, ttl) = engine.get_bytes_with_ttl(b"long".to_vec())?.unwrap();
+    assert_eq!(value, b"value");
+    assert!(ttl.is_some_and(|ttl| ttl > Duration::from_secs(50)));
+    engine.set("plain".to_owned(), "value".to_owned())?;
+    assert_eq!(
+        engine.get_bytes_with_ttl(b"plain".to_vec())?,
+        Some((b"value".to_vec(), None))
+    );
+    assert_eq!(engine.scan_prefix(b"short".to_vec())?.count(), 0);
+    assert!(matches!(
+        engine.remove("short".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    // an expired key is absent for a compare-and-swap.
+    assert!(engine.set_if_absent("short".to_owned(), "again".to_owned())?);
+    assert_eq!(engine.get("short".to_owned())?, Some("again".to_owned()));
+    Ok(())
+}
+
+fn check_compare_and_swap(engine: &impl KvEngine) -> Result<()> {
+    assert!(engine.set_if_absent("key".to_owned(), "1".to_owned())?);
+    assert!(!engine.set_if_absent("key".to_owned(), "2".to_owned())?);
+    assert!(!engine.compare_and_swap(
+        "key".to_owned(),
+        Some("2".to_owned()),
+        Some("3".to_owned())
+    )?);
+    assert!(engine.compare_and_swap(
+        "key".to_owned(),
+        Some("1".to_owned()),
+        Some("3".to_owned())
+    )?);
+    assert_eq!(engine.get("key".to_owned())?, Some("3".to_owned()));
+    assert!(engine.compare_and_swap("key".to_owned(), Some("3".to_owned()), None)?);
+    assert_eq!(engine.get("key".to_owned())?, None);
+
+    // concurrent increments never get lost.
+    engine.set("counter".to_owned(), "0".to_owned())?;
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let engine = engine.clone();
+            thread::spawn(move || -> Result<()> {
+                for _ in 0..25 {
+                    loop {
+                        let current = engine.get("counter".to_owned())?.unwrap();
+                        let next = (current.parse::<u32>().unwrap() + 1).to_string();
+                        if engine.compare_and_swap(
+                            "counter".to_owned(),
+                            Some(current),
+                            Some(next),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+    assert_eq!(engine.get("counter".to_owned())?, Some("100".to_owned()));
+    Ok(())
+}
+
+fn check_write_batch(engine: &impl KvEngine) -> Result<()> {
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch
+        .set("key2", "value2")
+        .remove("key1")
+        .set("key3", "value3")
+        .remove("key3");
+    engine.write_batch(batch)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(engine.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, None);
+
+    // a batch removing a missing key writes nothing.
+    let mut batch = WriteBatch::new();
+    batch.set("key4", "value4").remove("missing");
+    assert!(matches!(
+        engine.write_batch(batch),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(engine.get("key4".to_owned())?, None);
+    Ok(())
+}
+
+fn check_transact(engine: &impl KvEngine) -> Result<()> {
+    engine.set("balance".to_owned(), "10".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("balance", "5").set("spent", "5");
+    engine.transact(vec![(b"balance".to_vec(), Some(b"10".to_vec()))], batch)?;
+    assert_eq!(engine.get("balance".to_owned())?, Some("5".to_owned()));
+
+    // the read is stale now.
+    let mut batch = WriteBatch::new();
+    batch.set("balance", "0").set("spent", "10");
+    assert!(matches!(
+        engine.transact(vec![(b"balance".to_vec(), Some(b"10".to_vec()))], batch),
+        Err(KvsError::TxnConflict)
+    ));
+    assert_eq!(engine.get("spent".to_owned())?, Some("5".to_owned()));
+
+    // a missing key is read as `None`.
+    let mut batch = WriteBatch::new();
+    batch.set("new", "value");
+    engine.transact(vec![(b"new".to_vec(), None)], batch)?;
+    assert_eq!(engine.get("new".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+fn check_watch(engine: &impl KvEngine) -> Result<()> {
+    let mut watcher = engine.watch(b"user:".to_vec())?;
+    engine.set("user:1".to_owned(), "alice".to_owned())?;
+    engine.set("other".to_owned(), "value".to_owned())?;
+    engine.remove("user:1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2", "bob");
+    engine.write_batch(batch)?;
+    // removing an expired key writes nothing.
+    engine.set_with_ttl(
+        "user:3".to_owned(),
+        "carol".to_owned(),
+        Duration::from_millis(50),
+    )?;
+    thread::sleep(Duration::from_millis(100));
+    assert!(matches!(
+        engine.remove("user:3".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+
+    let timeout = Duration::from_secs(1);
+    let events: Vec<_> = (0..4).flat_map(|_| watcher.next_timeout(timeout)).collect();
+    assert_eq!(
+        events,
+        vec![
+            WatchEvent::Set {
+                key: b"user:1".to_vec(),
+                value: b"alice".to_vec(),
+            },
+            WatchEvent::Remove {
+                key: b"user:1".to_vec()
+            },
+            WatchEvent::Set {
+                key: b"user:2".to_vec(),
+                value: b"bob".to_vec(),
+            },
+            WatchEvent::Set {
+                key: b"user:3".to_vec(),
+                value: b"carol".to_vec(),
+            },
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+    Ok(())
+}
+
+fn check_engine<E: KvEngine>(open: impl Fn(&Path) -> Result<E>) -> Result<()> {
+    let checks: [fn(&E) -> Result<()>; 7] = [
+        check_basics,
+        check_scan,
+        check_ttl,
+        check_compare_and_swap,
+        check_write_batch,
+        check_transact,
+        check_watch,
+    ];
+    for check in &checks {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        check(&open(temp_dir.path())?)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn sled_engine() -> Result<()> {
+    check_engine(|path| SledKvsEngine::open(path))
+}
+
+// Should keep the data across reopening and lock the directory while open.
+#[test]
+fn sled_engine_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(
+        SledKvsEngine::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked { .. })
+    ));
+    drop(engine);
+
+    let engine = SledKvsEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    Ok(())
+}
+
+#[test]
+fn memory_engine() -> Result<()> {
+    check_engine(|_| Ok(MemoryEngine::new()))
+}
+
+// Should save the pairs to the snapshot file and load them back.
+#[test]
+fn memory_engine_snapshot() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let path = temp_dir.path().join("snapshot.json");
+    let engine = MemoryEngine::with_snapshot(&path)?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set_bytes(b"key2".to_vec(), vec![0, 159, 146, 150])?;
+    engine.set_with_ttl(
+        "short".to_owned(),
+        "value".to_owned(),
+        Duration::from_millis(100),
+    )?;
+    engine.set_with_ttl(
+        "long".to_owned(),
+        "value".to_owned(),
+        Duration::from_secs(60),
+    )?;
+    engine.flush()?;
+    assert!(path.exists());
+
+    // the last handle saves the latest writes.
+    let other = engine.clone();
+    drop(engine);
+    other.remove("key1".to_owned())?;
+    other.set("key3".to_owned(), "value3".to_owned())?;
+    drop(other);
+    thread::sleep(Duration::from_millis(200));
+
+    let engine = MemoryEngine::with_snapshot(&path)?;
+    assert_eq!(engine.get("key1".to_owned())?, None);
+    assert_eq!(
+        engine.get_bytes(b"key2".to_vec())?,
+        Some(vec![0, 159, 146, 150])
+    );
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    assert_eq!(engine.get("short".to_owned())?, None);
+    assert_eq!(engine.get("long".to_owned())?, Some("value".to_owned()));
+    Ok(())
+}
+
+#[test]
+fn lsm_engine() -> Result<()> {
+    check_engine(|path| LsmEngine::open(path))
+}
+
+// Should keep the data across reopening, replaying the writes which have not
+// been flushed to a table yet, and lock the directory while open.
+#[test]
+fn lsm_engine_reopen() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.remove("key2".to_owned())?;
+    assert!(matches!(
+        LsmEngine::open(temp_dir.path()),
+        Err(KvsError::DirectoryLocked { .. })
+    ));
+    drop(engine);
+
+    let engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key2".to_owned())?, None);
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    let engine = LsmEngine::open(temp_dir.path())?;
+    assert_eq!(engine.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(engine.get("key3".to_owned())?, Some("value3".to_owned()));
+    Ok(())
+}
+
+// A damaged length in the middle of the write-ahead log should be reported on
+// open instead of being taken for a torn write.
+#[test]
+fn lsm_engine_corrupted_wal() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let engine = LsmEngine::open(temp_dir.path())?;
+    engine.set("key1".to_owned(), "value1".to_owned())?;
+    engine.set("key2".to_owned(), "value2".to_owned())?;
+    engine.set("key3".to_owned(), "value3".to_owned())?;
+    drop(engine);
+
+    // Make the first write's length point past the end of the log.
+    let wal_path = fs::read_dir(temp_dir.path())?
+        .map(|entry| entry.unwrap().path())
+        .find(|path| path.extension() == Some("wal".as_ref()) && path.metadata().unwrap().len() > 0)
+        .expect("no write-ahead log written");
+    let wal_len = wal_path.metadata()?.len();
+    let mut wal = OpenOptions::new().write(true).open(&wal_path)?;
+    wal.seek(SeekFrom::Start(1))?;
+    wal.write_all(&[0x7f])?;
+    drop(wal);
+
+    match LsmEngine::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+    assert_eq!(wal_path.metadata()?.len(), wal_len);
+    Ok(())
+}
+
+// Should read the latest values while the memtable is flushed to tables
+// and the tables are compacted through the levels.
+#[test]
+fn lsm_engine_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = LsmOptions::new();
+    options
+        .memtable_size(4 * 1024)
+        .block_size(256)
+        .table_size(8 * 1024)
+        .level0_tables(2)
+        .level_base_size(16 * 1024);
+    let engine = LsmEngine::open_with(temp_dir.path(), &options)?;
+
+    for round in 0..5 {
+        for key_id in 0..1000 {
+            engine.set(format!("key{:04}", key_id), format!("value{}", round))?;
+        }
+    }
+    for key_id in (0..1000).step_by(3) {
+        engine.remove(format!("key{:04}", key_id))?;
+    }
+    let sst_count = || {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path().extension() == Some("sst".as_ref()))
+            .count()
+    };
+    assert!(sst_count() > 1);
+
+    let check = |engine: &LsmEngine| -> Result<()> {
+        for key_id in 0..1000 {
+            let expected = if key_id % 3 == 0 {
+                None
+            } else {
+                Some("value4".to_owned())
+            };
+            assert_eq!(engine.get(format!("key{:04}", key_id))?, expected);
+        }
+        let pairs = engine.scan(..)?.collect::<Result<Vec<_>>>()?;
+        assert_eq!(pairs.len(), 666);
+        assert!(pairs.windows(2).all(|pair| pair[0].0 < pair[1].0));
+        assert!(pairs.iter().all(|(_, value)| value == b"value4"));
+        Ok(())
+    };
+    check(&engine)?;
+    drop(engine);
+
+    let engine = LsmEngine::open_with(temp_dir.path(), &options)?;
+    check(&engine)?;
+    // overwritten and removed entries are dropped by the compactions,
+    // which run in the background: 5000 uncompacted entries take ~110KB.
+    let dir_size = || -> u64 {
+        fs::read_dir(temp_dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().metadata().unwrap().len())
+            .sum()
+    };
+    for _ in 0..50 {
+        if dir_size() < 64 * 1024 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert!(dir_size() < 64 * 1024);
+    Ok(())
+}
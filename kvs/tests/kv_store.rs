@@ -1,4 +1,10 @@
-use kvs::{KvEngine, KvStore, Result};
+use kvs::{
+    Codec, KvEngine, KvStore, KvStoreOptions, KvsError, Result, Sync, WatchEvent, WriteBatch,
+};
+use std::fs::OpenOptions;
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 use tempfile::TempDir;
 use walkdir::WalkDir;
 
@@ -6,7 +12,7 @@ use walkdir::WalkDir;
 #[test]
 fn get_stored_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     store.set("key2".to_owned(), "value2".to_owned())?;
@@ -16,7 +22,7 @@ fn get_stored_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
     assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
 
@@ -27,7 +33,7 @@ fn get_stored_value() -> Result<()> {
 #[test]
 fn overwrite_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
@@ -36,7 +42,7 @@ fn overwrite_value() -> Result<()> {
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
     store.set("key1".to_owned(), "value3".to_owned())?;
     assert_eq!(store.get("key1".to_owned())?, Some("value3".to_owned()));
@@ -48,14 +54,14 @@ fn overwrite_value() -> Result<()> {
 #[test]
 fn get_non_existent_value() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     // Open from disk again and check persistent data
     drop(store);
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert_eq!(store.get("key2".to_owned())?, None);
 
     Ok(())
@@ -64,7 +70,7 @@ fn get_non_existent_value() -> Result<()> {
 #[test]
 fn remove_non_existent_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     assert!(store.remove("key1".to_owned()).is_err());
     Ok(())
 }
@@ -72,7 +78,7 @@ fn remove_non_existent_key() -> Result<()> {
 #[test]
 fn remove_key() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
     store.set("key1".to_owned(), "value1".to_owned())?;
     assert!(store.remove("key1".to_owned()).is_ok());
     assert_eq!(store.get("key1".to_owned())?, None);
@@ -84,7 +90,7 @@ fn remove_key() -> Result<()> {
 #[test]
 fn compaction() -> Result<()> {
     let temp_dir = TempDir::new().expect("unable to create temporary working directory");
-    let mut store = KvStore::open(temp_dir.path())?;
+    let store = KvStore::open(temp_dir.path())?;
 
     let dir_size = || {
         let entries = WalkDir::new(temp_dir.path()).into_iter();
@@ -114,7 +120,7 @@ fn compaction() -> Result<()> {
 
         drop(store);
         // reopen and check content
-        let mut store = KvStore::open(temp_dir.path())?;
+        let store = KvStore::open(temp_dir.path())?;
         for key_id in 0..1000 {
             let key = format!("key{}", key_id);
             assert_eq!(store.get(key)?, Some(format!("{}", iter)));
@@ -124,3 +130,1202 @@ fn compaction() -> Result<()> {
 
     panic!("No compaction detected");
 }
+
+// A low compaction threshold should keep only live entries on disk.
+#[test]
+fn compaction_threshold() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options.compaction_threshold(0);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+
+    let dir_size = || {
+        let entries = WalkDir::new(temp_dir.path()).into_iter();
+        let len: walkdir::Result<u64> = entries
+            .map(|res| {
+                res.and_then(|entry| entry.metadata())
+                    .map(|metadata| metadata.len())
+            })
+            .sum();
+        len.expect("fail to get directory size")
+    };
+
+    for iter in 0..1000 {
+        store.set("key".to_owned(), format!("{}", iter))?;
+    }
+    // compaction runs in the background, wait for the last one.
+    store.compact_now()?;
+    // 1000 uncompacted entries would take ~40KB
+    assert!(dir_size() < 10 * 1024);
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("999".to_owned()));
+
+    Ok(())
+}
+
+// Writes made after the compaction is scheduled should survive it.
+#[test]
+fn compact_now() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..1000 {
+        store.set(format!("key{}", key_id), "old".to_owned())?;
+        store.set(format!("key{}", key_id), "new".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact_now()?;
+    store.set("key1".to_owned(), "newest".to_owned())?;
+    store.compact_now()?;
+
+    let check = |store: &KvStore| -> Result<()> {
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("newest".to_owned()));
+        for key_id in 2..1000 {
+            assert_eq!(store.get(format!("key{}", key_id))?, Some("new".to_owned()));
+        }
+        Ok(())
+    };
+    check(&store)?;
+
+    // Open from disk again and check persistent data
+    drop(store);
+    check(&KvStore::open(temp_dir.path())?)?;
+
+    Ok(())
+}
+
+// Compacted logs should get a hint file used to rebuild the index on open.
+#[test]
+fn hint_files() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "value".to_owned())?;
+    }
+    store.remove("key0".to_owned())?;
+    store.compact_now()?;
+    store.set("key1".to_owned(), "newer".to_owned())?;
+    drop(store);
+
+    let hint_files: Vec<_> = WalkDir::new(temp_dir.path())
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("hint".as_ref()))
+        .collect();
+    assert_eq!(hint_files.len(), 1);
+
+    let check = || -> Result<()> {
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key0".to_owned())?, None);
+        assert_eq!(store.get("key1".to_owned())?, Some("newer".to_owned()));
+        for key_id in 2..100 {
+            assert_eq!(
+                store.get(format!("key{}", key_id))?,
+                Some("value".to_owned())
+            );
+        }
+        Ok(())
+    };
+    check()?;
+
+    // Without the hint file the log is replayed instead.
+    std::fs::remove_file(hint_files[0].path())?;
+    check()?;
+
+    Ok(())
+}
+
+// Returns the only non-empty log file in the directory.
+fn written_log(dir: &Path) -> PathBuf {
+    let logs: Vec<_> = WalkDir::new(dir)
+        .into_iter()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+        .filter(|entry| entry.metadata().map(|m| m.len() > 0).unwrap_or(false))
+        .map(|entry| entry.into_path())
+        .collect();
+    assert_eq!(logs.len(), 1);
+    logs.into_iter().next().unwrap()
+}
+
+// A record cut off at the end of the log should be discarded on open.
+#[test]
+fn torn_tail() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Half of a frame header: a write interrupted by a crash.
+    let mut log = OpenOptions::new()
+        .append(true)
+        .open(written_log(temp_dir.path()))?;
+    log.write_all(&[42, 0, 0])?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+
+    Ok(())
+}
+
+// A damaged record in the middle of the log should be reported on open.
+#[test]
+fn corrupted_record() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    drop(store);
+
+    // Overwrite a byte of the first record's payload.
+    let mut log = OpenOptions::new()
+        .write(true)
+        .open(written_log(temp_dir.path()))?;
+    log.seek(SeekFrom::Start(12))?;
+    log.write_all(b"#")?;
+    drop(log);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+
+    Ok(())
+}
+
+// A damaged length in the middle of the log should be reported on open
+// instead of being taken for a torn write.
+#[test]
+fn corrupted_length() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    store.set("key3".to_owned(), "value3".to_owned())?;
+    drop(store);
+
+    // Make the first record's length point past the end of the log.
+    let log_path = written_log(temp_dir.path());
+    let log_len = log_path.metadata()?.len();
+    let mut log = OpenOptions::new().write(true).open(&log_path)?;
+    log.seek(SeekFrom::Start(1))?;
+    log.write_all(&[0x7f])?;
+    drop(log);
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::Corruption { offset, .. }) => assert_eq!(offset, 0),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("corruption is not detected"),
+    }
+    assert_eq!(log_path.metadata()?.len(), log_len);
+
+    Ok(())
+}
+
+// Data written in every durability mode should be readable after reopen.
+#[test]
+fn sync_modes() -> Result<()> {
+    for &sync in &[
+        Sync::Always,
+        Sync::Interval(Duration::from_millis(10)),
+        Sync::Never,
+    ] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.sync(sync);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        store.compact_now()?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        store.flush()?;
+
+        drop(store);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Concurrent writes through cloned handles should all be persisted.
+#[test]
+fn concurrent_writers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options.sync(Sync::Always);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+
+    let handles: Vec<_> = (0..8)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for key_id in 0..100 {
+                    store.set(
+                        format!("key{}-{}", thread_id, key_id),
+                        format!("value{}", key_id),
+                    )?;
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    drop(store);
+    let store = KvStore::open(temp_dir.path())?;
+    for thread_id in 0..8 {
+        for key_id in 0..100 {
+            assert_eq!(
+                store.get(format!("key{}-{}", thread_id, key_id))?,
+                Some(format!("value{}", key_id))
+            );
+        }
+    }
+
+    Ok(())
+}
+
+// Every codec should round-trip the data, and an existing directory should
+// be opened with the codec it was created with.
+#[test]
+fn codecs() -> Result<()> {
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.codec(codec);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+        store.set("key2".to_owned(), "value2".to_owned())?;
+        store.remove("key1".to_owned())?;
+        store.compact_now()?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        drop(store);
+
+        let mut other_options = KvStoreOptions::new();
+        other_options.codec(if codec == Codec::Binary {
+            Codec::Json
+        } else {
+            Codec::Binary
+        });
+        let store = KvStore::open_with(temp_dir.path(), &other_options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        store.set("key4".to_owned(), "value4".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key4".to_owned())?, Some("value4".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Keys and values which are not valid UTF-8 survive every codec, compaction
+// and reopening.
+#[test]
+fn binary_values() -> Result<()> {
+    let key = vec![0xff, 0x00, b'k'];
+    let value = vec![0x80, 0xfe, 0x00, 0x01];
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.codec(codec);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        store.set_bytes(key.clone(), value.clone())?;
+        store.set_bytes(b"text".to_vec(), value.clone())?;
+        store.set_bytes(b"empty".to_vec(), Vec::new())?;
+        assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+        store.compact_now()?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get_bytes(key.clone())?, Some(value.clone()));
+        assert_eq!(store.get_bytes(b"empty".to_vec())?, Some(Vec::new()));
+        match store.get("text".to_owned()) {
+            Err(KvsError::Utf8(_)) => {}
+            res => panic!("expected a UTF-8 error, got {:?}", res),
+        }
+        store.remove_bytes(key.clone())?;
+        assert_eq!(store.get_bytes(key.clone())?, None);
+    }
+
+    Ok(())
+}
+
+// Should list the keys of a range or a prefix in sorted order.
+#[test]
+fn scans() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for key in &[
+        "user:2:name",
+        "user:1:name",
+        "user:10:name",
+        "admin",
+        "user;",
+        "zz",
+    ] {
+        store.set(key.to_string(), format!("{}-value", key))?;
+    }
+    store.set_bytes(vec![0xff, 0xff], b"high".to_vec())?;
+    store.set_bytes(vec![0xff, 0xff, 0x00], b"higher".to_vec())?;
+    store.remove("user:2:name".to_owned())?;
+
+    let keys = |scan: kvs::Scan| -> Result<Vec<Vec<u8>>> {
+        scan.map(|pair| pair.map(|(key, _)| key)).collect()
+    };
+    assert_eq!(
+        keys(store.scan_prefix(b"user:".to_vec())?)?,
+        vec![b"user:10:name".to_vec(), b"user:1:name".to_vec()]
+    );
+    assert_eq!(
+        keys(store.scan(b"user".to_vec()..=b"zz".to_vec())?)?,
+        vec![
+            b"user:10:name".to_vec(),
+            b"user:1:name".to_vec(),
+            b"user;".to_vec(),
+            b"zz".to_vec()
+        ]
+    );
+    assert_eq!(
+        keys(store.scan_prefix(vec![0xff, 0xff])?)?,
+        vec![vec![0xff, 0xff], vec![0xff, 0xff, 0x00]]
+    );
+    assert_eq!(keys(store.scan(..)?)?.len(), 7);
+
+    let pairs: Vec<(Vec<u8>, Vec<u8>)> = store
+        .scan(b"a".to_vec()..b"b".to_vec())?
+        .collect::<Result<_>>()?;
+    assert_eq!(pairs, vec![(b"admin".to_vec(), b"admin-value".to_vec())]);
+
+    // values are read lazily, after the compaction moved them.
+    let scan_store = store.clone();
+    let mut scan = scan_store.scan_prefix(b"user:".to_vec())?;
+    store.remove("user:10:name".to_owned())?;
+    store.compact_now()?;
+    let (key, value) = scan.next().unwrap()?;
+    assert_eq!(key, b"user:1:name".to_vec());
+    assert_eq!(value, b"user:1:name-value".to_vec());
+    assert!(scan.next().is_none());
+
+    Ok(())
+}
+
+// Keys set with a TTL should disappear once it passes, also after
+// reopening and compacting the store.
+#[test]
+fn ttl() -> Result<()> {
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options
+            .codec(codec)
+            .ttl_sweep_interval(Duration::from_millis(50));
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        store.set("key1".to_owned(), "old".to_owned())?;
+        store.set_with_ttl(
+            "key1".to_owned(),
+            "value1".to_owned(),
+            Duration::from_millis(300),
+        )?;
+        store.set_with_ttl(
+            "key2".to_owned(),
+            "value2".to_owned(),
+            Duration::from_secs(3600),
+        )?;
+        store.set_with_ttl(
+            "key3".to_owned(),
+            "value3".to_owned(),
+            Duration::from_millis(300),
+        )?;
+        store.set("key3".to_owned(), "value3".to_owned())?;
+        assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+        std::thread::sleep(Duration::from_millis(400));
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        // a plain set clears the TTL.
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        assert_eq!(
+            store.get_bytes_with_ttl(b"key3".to_vec())?,
+            Some((b"value3".to_vec(), None))
+        );
+        let (_, ttl) = store.get_bytes_with_ttl(b"key2".to_vec())?.unwrap();
+        assert!(ttl.is_some_and(|ttl| ttl > Duration::from_secs(3500)));
+        assert_eq!(store.get_bytes_with_ttl(b"key1".to_vec())?, None);
+        assert!(matches!(
+            store.remove("key1".to_owned()),
+            Err(KvsError::KeyNotFound)
+        ));
+        let keys: Vec<Vec<u8>> = store
+            .scan(..)?
+            .map(|pair| pair.map(|(key, _)| key))
+            .collect::<Result<_>>()?;
+        assert_eq!(keys, vec![b"key2".to_vec(), b"key3".to_vec()]);
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        store.compact_now()?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+        store.set_with_ttl("key4".to_owned(), "value4".to_owned(), Duration::ZERO)?;
+        assert_eq!(store.get("key4".to_owned())?, None);
+    }
+
+    Ok(())
+}
+
+// Expiry times beyond the signed 64-bit range should be stored by every
+// codec, BSON having no unsigned integers.
+#[test]
+fn ttl_beyond_i64() -> Result<()> {
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.codec(codec);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        // expiring right around `i64::MAX` and at `u64::MAX` milliseconds.
+        let limit = Duration::from_millis(i64::MAX as u64);
+        store.set_with_ttl("key1".to_owned(), "value1".to_owned(), limit)?;
+        store.set_with_ttl("key2".to_owned(), "value2".to_owned(), Duration::MAX)?;
+        drop(store);
+
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        let (value, ttl) = store.get_bytes_with_ttl(b"key1".to_vec())?.unwrap();
+        assert_eq!(value, b"value1");
+        assert!(ttl.unwrap() > limit - Duration::from_secs(60));
+        let (value, ttl) = store.get_bytes_with_ttl(b"key2".to_vec())?.unwrap();
+        assert_eq!(value, b"value2");
+        assert!(ttl.unwrap() > limit);
+    }
+
+    Ok(())
+}
+
+// A write batch should be applied as a whole or not at all.
+#[test]
+fn write_batch() -> Result<()> {
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.codec(codec);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+        store.set("key1".to_owned(), "value1".to_owned())?;
+
+        let mut batch = WriteBatch::new();
+        batch
+            .set("key2", "value2")
+            .set("key3", "value3")
+            .remove("key1")
+            .remove("key3")
+            .set(vec![0xff], vec![0x00, 0xfe]);
+        assert_eq!(batch.len(), 5);
+        store.write_batch(batch)?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get("key3".to_owned())?, None);
+        assert_eq!(store.get_bytes(vec![0xff])?, Some(vec![0x00, 0xfe]));
+
+        // removing a missing key fails the whole batch.
+        let mut batch = WriteBatch::new();
+        batch.set("key4", "value4").remove("key1");
+        assert!(matches!(
+            store.write_batch(batch),
+            Err(KvsError::KeyNotFound)
+        ));
+        assert_eq!(store.get("key4".to_owned())?, None);
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key1".to_owned())?, None);
+        assert_eq!(store.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(store.get_bytes(vec![0xff])?, Some(vec![0x00, 0xfe]));
+        store.compact_now()?;
+        store.set("key2".to_owned(), "value2-new".to_owned())?;
+        drop(store);
+
+        let store = KvStore::open(temp_dir.path())?;
+        assert_eq!(store.get("key2".to_owned())?, Some("value2-new".to_owned()));
+        assert_eq!(store.get_bytes(vec![0xff])?, Some(vec![0x00, 0xfe]));
+    }
+
+    Ok(())
+}
+
+// A batch cut off by a crash should leave none of its writes behind.
+#[test]
+fn torn_write_batch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key2", "value2").set("key3", "value3");
+    store.write_batch(batch)?;
+    drop(store);
+
+    // Only the first half of the batch reached the disk.
+    let log_path = written_log(temp_dir.path());
+    let log = OpenOptions::new().write(true).open(&log_path)?;
+    let len = log.metadata()?.len();
+    log.set_len(len - 20)?;
+    drop(log);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, None);
+
+    Ok(())
+}
+
+// Conditional writes should only apply when the current value matches.
+#[test]
+fn compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    assert!(store.set_if_absent("key1".to_owned(), "value1".to_owned())?);
+    assert!(!store.set_if_absent("key1".to_owned(), "other".to_owned())?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value1".to_owned()));
+
+    assert!(!store.compare_and_swap(
+        "key1".to_owned(),
+        Some("wrong".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert!(!store.compare_and_swap("key1".to_owned(), None, Some("value2".to_owned()))?);
+    assert!(store.compare_and_swap(
+        "key1".to_owned(),
+        Some("value1".to_owned()),
+        Some("value2".to_owned())
+    )?);
+    assert_eq!(store.get("key1".to_owned())?, Some("value2".to_owned()));
+
+    // `None` as the new value removes the key.
+    assert!(store.compare_and_swap("key1".to_owned(), Some("value2".to_owned()), None)?);
+    assert_eq!(store.get("key1".to_owned())?, None);
+    assert!(store.compare_and_swap("key1".to_owned(), None, None)?);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, None);
+
+    Ok(())
+}
+
+// Concurrent increments through compare-and-swap should not lose updates.
+#[test]
+fn concurrent_compare_and_swap() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+
+    let handles: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for _ in 0..50 {
+                    loop {
+                        let current = store.get("counter".to_owned())?;
+                        let next = current.as_ref().map_or(0, |n| n.parse::<u32>().unwrap()) + 1;
+                        if store.compare_and_swap(
+                            "counter".to_owned(),
+                            current,
+                            Some(next.to_string()),
+                        )? {
+                            break;
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let store = store;
+    assert_eq!(store.get("counter".to_owned())?, Some("200".to_owned()));
+
+    Ok(())
+}
+
+// A snapshot should keep seeing the store as it was when it was taken,
+// across later writes and compactions.
+#[test]
+fn snapshots() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let log_size = || -> u64 {
+        WalkDir::new(temp_dir.path())
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().extension() == Some("log".as_ref()))
+            .map(|entry| entry.metadata().unwrap().len())
+            .sum()
+    };
+
+    store.set("key1".to_owned(), "value1".to_owned())?;
+    store.set("key2".to_owned(), "value2".to_owned())?;
+    let snapshot = store.snapshot();
+
+    store.set("key1".to_owned(), "value1-new".to_owned())?;
+    store.remove("key2".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("key3", "value3").set("key1", "value1-batch");
+    store.write_batch(batch)?;
+    store.compact_now()?;
+
+    let check = |snapshot: &kvs::Snapshot| -> Result<()> {
+        assert_eq!(snapshot.get("key1".to_owned())?, Some("value1".to_owned()));
+        assert_eq!(snapshot.get("key2".to_owned())?, Some("value2".to_owned()));
+        assert_eq!(snapshot.get("key3".to_owned())?, None);
+        let pairs: Vec<_> = snapshot.scan(..)?.collect::<Result<_>>()?;
+        assert_eq!(
+            pairs,
+            vec![
+                (b"key1".to_vec(), b"value1".to_vec()),
+                (b"key2".to_vec(), b"value2".to_vec()),
+            ]
+        );
+        Ok(())
+    };
+    check(&snapshot)?;
+    assert_eq!(
+        store.get("key1".to_owned())?,
+        Some("value1-batch".to_owned())
+    );
+    assert_eq!(store.get("key2".to_owned())?, None);
+
+    // a snapshot of the batch sees all of its writes.
+    let latest = store.snapshot();
+    assert_eq!(
+        latest.get("key1".to_owned())?,
+        Some("value1-batch".to_owned())
+    );
+    assert_eq!(latest.get("key3".to_owned())?, Some("value3".to_owned()));
+    drop(latest);
+
+    for iter in 0..100 {
+        store.set("key1".to_owned(), format!("{}", iter))?;
+        store.compact_now()?;
+        check(&snapshot)?;
+    }
+
+    // once the snapshot is gone, compaction drops the old versions.
+    store.compact_now()?;
+    let pinned_size = log_size();
+    drop(snapshot);
+    store.compact_now()?;
+    assert!(log_size() < pinned_size);
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key1".to_owned())?, Some("99".to_owned()));
+    assert_eq!(store.get("key2".to_owned())?, None);
+    assert_eq!(store.get("key3".to_owned())?, Some("value3".to_owned()));
+    let snapshot = store.snapshot();
+    store.set("key2".to_owned(), "value2-new".to_owned())?;
+    assert_eq!(snapshot.get("key2".to_owned())?, None);
+
+    Ok(())
+}
+
+// A transaction should commit only if the keys it read are unchanged.
+#[test]
+fn transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("from".to_owned(), "10".to_owned())?;
+    store.set("to".to_owned(), "0".to_owned())?;
+
+    // reads see the writes of the transaction, the store sees them on commit.
+    let mut txn = store.transaction();
+    txn.set("from".to_owned(), "9".to_owned());
+    assert_eq!(txn.get("from".to_owned())?, Some("9".to_owned()));
+    assert_eq!(txn.get("to".to_owned())?, Some("0".to_owned()));
+    txn.set("to".to_owned(), "1".to_owned());
+    assert!(matches!(
+        txn.remove("missing".to_owned()),
+        Err(KvsError::KeyNotFound)
+    ));
+    assert_eq!(store.get("from".to_owned())?, Some("10".to_owned()));
+    txn.commit()?;
+    assert_eq!(store.get("from".to_owned())?, Some("9".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("1".to_owned()));
+
+    // a key set and removed by the transaction alone is not written at all.
+    let mut watcher = store.watch(b"temp".to_vec())?;
+    let mut txn = store.transaction();
+    txn.set("temp".to_owned(), "value".to_owned());
+    txn.remove("temp".to_owned())?;
+    txn.commit()?;
+    assert_eq!(store.get("temp".to_owned())?, None);
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+
+    // a write to a key read by the transaction makes it fail.
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("from".to_owned())?, Some("9".to_owned()));
+    txn.set("to".to_owned(), "2".to_owned());
+    store.set("from".to_owned(), "100".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TxnConflict)));
+    assert_eq!(store.get("to".to_owned())?, Some("1".to_owned()));
+
+    // so does a removal, while writes to other keys don't.
+    let mut txn = store.transaction();
+    txn.remove("to".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    let mut conflicting = store.transaction();
+    assert_eq!(conflicting.get("to".to_owned())?, Some("1".to_owned()));
+    conflicting.set("from".to_owned(), "0".to_owned());
+    txn.commit()?;
+    assert!(matches!(conflicting.commit(), Err(KvsError::TxnConflict)));
+    assert_eq!(store.get("to".to_owned())?, None);
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+
+    // as do a set and a removal of a key read as missing.
+    let mut txn = store.transaction();
+    assert_eq!(txn.get("flag".to_owned())?, None);
+    txn.set("from".to_owned(), "0".to_owned());
+    store.set("flag".to_owned(), "on".to_owned())?;
+    store.remove("flag".to_owned())?;
+    assert!(matches!(txn.commit(), Err(KvsError::TxnConflict)));
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+
+    // `transact` checks the values the keys were read with.
+    let mut batch = WriteBatch::new();
+    batch.set("to", "5");
+    assert!(matches!(
+        store.transact(
+            vec![(b"from".to_vec(), Some(b"10".to_vec()))],
+            batch.clone()
+        ),
+        Err(KvsError::TxnConflict)
+    ));
+    store.transact(
+        vec![
+            (b"from".to_vec(), Some(b"100".to_vec())),
+            (b"to".to_vec(), None),
+        ],
+        batch,
+    )?;
+    drop(store);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("from".to_owned())?, Some("100".to_owned()));
+    assert_eq!(store.get("to".to_owned())?, Some("5".to_owned()));
+
+    Ok(())
+}
+
+// Concurrent transfers through transactions should keep the total intact.
+#[test]
+fn concurrent_transactions() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    for account in 0..4 {
+        store.set(format!("account{}", account), "100".to_owned())?;
+    }
+
+    let handles: Vec<_> = (0..4)
+        .map(|thread_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 0..50 {
+                    let from = format!("account{}", thread_id);
+                    let to = format!("account{}", (thread_id + iter + 1) % 4);
+                    if from == to {
+                        continue;
+                    }
+                    loop {
+                        let mut txn = store.transaction();
+                        let balance = |txn: &mut kvs::Transaction, key: &str| -> Result<i64> {
+                            Ok(txn.get(key.to_owned())?.unwrap().parse().unwrap())
+                        };
+                        let from_balance = balance(&mut txn, &from)?;
+                        let to_balance = balance(&mut txn, &to)?;
+                        txn.set(from.clone(), (from_balance - 1).to_string());
+                        txn.set(to.clone(), (to_balance + 1).to_string());
+                        match txn.commit() {
+                            Ok(()) => break,
+                            Err(KvsError::TxnConflict) => continue,
+                            Err(e) => return Err(e),
+                        }
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for handle in handles {
+        handle.join().unwrap()?;
+    }
+
+    let mut total = 0;
+    for account in 0..4 {
+        let balance: i64 = store
+            .get(format!("account{}", account))?
+            .unwrap()
+            .parse()
+            .unwrap();
+        total += balance;
+    }
+    assert_eq!(total, 400);
+
+    Ok(())
+}
+
+// Older versions should be listed and readable as long as they are retained.
+#[test]
+fn key_history() -> Result<()> {
+    for &codec in &[Codec::Json, Codec::Bson, Codec::Binary] {
+        let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+        let mut options = KvStoreOptions::new();
+        options.codec(codec).retain_versions(3);
+        let store = KvStore::open_with(temp_dir.path(), &options)?;
+
+        let mut versions = Vec::new();
+        for iter in 0..5 {
+            store.set("key".to_owned(), format!("value{}", iter))?;
+            versions.push(store.version());
+        }
+        store.remove("key".to_owned())?;
+        versions.push(store.version());
+        assert!(versions.windows(2).all(|pair| pair[0] < pair[1]));
+
+        let check = |store: &KvStore| -> Result<()> {
+            let history = store.history(b"key".to_vec())?;
+            let listed: Vec<_> = history
+                .iter()
+                .map(|version| (version.version, version.value.clone()))
+                .collect();
+            assert_eq!(
+                listed,
+                vec![
+                    (versions[3], Some(b"value3".to_vec())),
+                    (versions[4], Some(b"value4".to_vec())),
+                    (versions[5], None),
+                ]
+            );
+            assert_eq!(
+                store.get_at(b"key".to_vec(), versions[3])?,
+                Some(b"value3".to_vec())
+            );
+            assert_eq!(
+                store.get_at(b"key".to_vec(), versions[4])?,
+                Some(b"value4".to_vec())
+            );
+            assert_eq!(store.get_at(b"key".to_vec(), versions[5])?, None);
+            // the versions before are gone.
+            assert_eq!(store.get_at(b"key".to_vec(), versions[2])?, None);
+            Ok(())
+        };
+        check(&store)?;
+        store.compact_now()?;
+        check(&store)?;
+        drop(store);
+        check(&KvStore::open_with(temp_dir.path(), &options)?)?;
+    }
+
+    // only the current version is kept by default.
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let version = store.version();
+    store.set("key".to_owned(), "value2".to_owned())?;
+    assert_eq!(store.history(b"key".to_vec())?.len(), 1);
+    assert_eq!(store.get_at(b"key".to_vec(), version)?, None);
+    assert_eq!(store.history(b"missing".to_vec())?, vec![]);
+
+    Ok(())
+}
+
+// Overwritten versions should be kept for the retention duration only.
+#[test]
+fn key_history_retention_duration() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options
+        .retain_for(Duration::from_millis(500))
+        .ttl_sweep_interval(Duration::from_millis(50));
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+
+    store.set("key".to_owned(), "value1".to_owned())?;
+    let version = store.version();
+    store.set("key".to_owned(), "value2".to_owned())?;
+    store.compact_now()?;
+    assert_eq!(store.history(b"key".to_vec())?.len(), 2);
+    assert_eq!(
+        store.get_at(b"key".to_vec(), version)?,
+        Some(b"value1".to_vec())
+    );
+
+    std::thread::sleep(Duration::from_millis(700));
+    store.compact_now()?;
+    let history = store.history(b"key".to_vec())?;
+    assert_eq!(history.len(), 1);
+    assert_eq!(history[0].value, Some(b"value2".to_vec()));
+    assert_eq!(store.get_at(b"key".to_vec(), version)?, None);
+
+    Ok(())
+}
+
+// Watchers should get the writes to their keys in order.
+#[test]
+fn watch() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("user:0".to_owned(), "before".to_owned())?;
+    let mut watcher = store.watch(b"user:".to_vec())?;
+    let mut all = store.watch(Vec::new())?;
+
+    store.set("user:1".to_owned(), "alice".to_owned())?;
+    store.set("other".to_owned(), "value".to_owned())?;
+    store.remove("user:1".to_owned())?;
+    let mut batch = WriteBatch::new();
+    batch.set("user:2", "bob").set("other", "value2");
+    store.write_batch(batch)?;
+    // writes through other handles are reported too.
+    let other = store.clone();
+    std::thread::spawn(move || other.set("user:3".to_owned(), "carol".to_owned()))
+        .join()
+        .unwrap()?;
+
+    let set = |key: &str, value: &str| WatchEvent::Set {
+        key: key.as_bytes().to_vec(),
+        value: value.as_bytes().to_vec(),
+    };
+    let timeout = Duration::from_secs(1);
+    let events: Vec<_> = (0..4).flat_map(|_| watcher.next_timeout(timeout)).collect();
+    assert_eq!(
+        events,
+        vec![
+            set("user:1", "alice"),
+            WatchEvent::Remove {
+                key: b"user:1".to_vec()
+            },
+            set("user:2", "bob"),
+            set("user:3", "carol"),
+        ]
+    );
+    assert_eq!(watcher.next_timeout(Duration::from_millis(100)), None);
+    assert_eq!(all.next_timeout(timeout), Some(set("user:1", "alice")));
+    assert_eq!(all.next_timeout(timeout), Some(set("other", "value")));
+
+    // dropping a watcher doesn't affect the others or the writes.
+    drop(watcher);
+    store.set("user:4".to_owned(), "dave".to_owned())?;
+    assert_eq!(all.by_ref().nth(4), Some(set("user:4", "dave")));
+
+    // the iteration ends with the store.
+    drop(store);
+    assert_eq!(all.next(), None);
+
+    Ok(())
+}
+
+// A watcher which doesn't take its events should be disconnected once its
+// queue is full, instead of queueing the writes without a limit.
+#[test]
+fn slow_watcher() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    let watcher = store.watch(b"key".to_vec())?;
+    let mut batch = WriteBatch::new();
+    for key_id in 0..5000 {
+        batch.set(format!("key{}", key_id), "value");
+    }
+    store.write_batch(batch)?;
+
+    // the queued events are still delivered, then the iteration ends.
+    assert_eq!(watcher.count(), 4096);
+    // and the writes go on without it.
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    Ok(())
+}
+
+// Readers on cloned handles should run in parallel with a writer.
+#[test]
+fn concurrent_readers() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options.compaction_threshold(64 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for key_id in 0..100 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    let writer = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for iter in 1..=50 {
+                for key_id in 0..100 {
+                    store.set(format!("key{}", key_id), iter.to_string())?;
+                }
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                // values only ever grow, and the writer goes key by key.
+                let mut last = vec![0; 100];
+                for _ in 0..20 {
+                    for (key_id, last) in last.iter_mut().enumerate() {
+                        let value: u32 = store
+                            .get(format!("key{}", key_id))?
+                            .expect("key is missing")
+                            .parse()
+                            .unwrap();
+                        assert!(value >= *last);
+                        *last = value;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    writer.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for key_id in 0..100 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("50".to_owned()));
+    }
+
+    Ok(())
+}
+
+// Should refuse to open a directory which is already open.
+#[test]
+fn directory_lock() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let store = KvStore::open(temp_dir.path())?;
+    store.set("key".to_owned(), "value".to_owned())?;
+
+    match KvStore::open(temp_dir.path()) {
+        Err(KvsError::DirectoryLocked { pid }) => assert_eq!(pid, std::process::id()),
+        _ => panic!("directory is not locked"),
+    }
+    // the lock is held as long as any handle is alive.
+    let handle = store.clone();
+    drop(store);
+    assert!(KvStore::open(temp_dir.path()).is_err());
+    drop(handle);
+
+    let store = KvStore::open(temp_dir.path())?;
+    assert_eq!(store.get("key".to_owned())?, Some("value".to_owned()));
+
+    Ok(())
+}
+
+// Readers, writers and compactions running at the same time: a read never
+// misses a key nor sees a value older than the one it saw before.
+#[test]
+fn concurrent_reads_writes_compaction() -> Result<()> {
+    let temp_dir = TempDir::new().expect("unable to create temporary working directory");
+    let mut options = KvStoreOptions::new();
+    options.compaction_threshold(16 * 1024);
+    let store = KvStore::open_with(temp_dir.path(), &options)?;
+    for key_id in 0..200 {
+        store.set(format!("key{}", key_id), "0".to_owned())?;
+    }
+
+    // every writer owns a slice of the keys.
+    let writers: Vec<_> = (0..2)
+        .map(|writer_id| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                for iter in 1..=30 {
+                    for key_id in (writer_id * 100)..(writer_id * 100 + 100) {
+                        store.set(format!("key{}", key_id), iter.to_string())?;
+                    }
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    let compactor = {
+        let store = store.clone();
+        std::thread::spawn(move || -> Result<()> {
+            for _ in 0..10 {
+                store.compact_now()?;
+            }
+            Ok(())
+        })
+    };
+    let readers: Vec<_> = (0..4)
+        .map(|_| {
+            let store = store.clone();
+            std::thread::spawn(move || -> Result<()> {
+                let mut last = vec![0; 200];
+                for _ in 0..10 {
+                    for (key_id, last) in last.iter_mut().enumerate() {
+                        let value: u32 = store
+                            .get(format!("key{}", key_id))?
+                            .expect("key is missing")
+                            .parse()
+                            .unwrap();
+                        assert!(value >= *last);
+                        *last = value;
+                    }
+                    let snapshot = store.snapshot();
+                    let pairs = snapshot.scan(..)?.collect::<Result<Vec<_>>>()?;
+                    assert_eq!(pairs.len(), 200);
+                }
+                Ok(())
+            })
+        })
+        .collect();
+    for writer in writers {
+        writer.join().unwrap()?;
+    }
+    compactor.join().unwrap()?;
+    for reader in readers {
+        reader.join().unwrap()?;
+    }
+
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("30".to_owned()));
+    }
+    drop(store);
+
+    // the compacted store opens with every key in place.
+    let store = KvStore::open(temp_dir.path())?;
+    for key_id in 0..200 {
+        assert_eq!(store.get(format!("key{}", key_id))?, Some("30".to_owned()));
+    }
+
+    Ok(())
+}